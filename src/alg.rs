@@ -1,7 +1,9 @@
 use core::fmt;
 use std::{io, path::Path, str::FromStr};
 
-#[derive(Clone, Copy, Debug, Default)]
+use digest::DynDigest;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub enum Algorithm {
     Blake3,
     Md5,
@@ -12,10 +14,71 @@ pub enum Algorithm {
 }
 
 impl Algorithm {
-    #[inline]
+    pub const ALL: [Algorithm; 5] = [
+        Algorithm::Blake3,
+        Algorithm::Md5,
+        Algorithm::Sha1,
+        Algorithm::Sha256,
+        Algorithm::Sha512,
+    ];
+
+    /// Hash the file at `path`, consulting the digest cache if one is in use.
     pub fn hash(self, path: impl AsRef<Path>) -> io::Result<String> {
+        let path = path.as_ref();
+        match crate::cache::get() {
+            Some(cache) => cache.hash(self, path, |path| self.hash_uncached(path)),
+            None => self.hash_uncached(path),
+        }
+    }
+
+    /// A fresh digest, for hashing data other than whole files.
+    pub fn digest(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            Algorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            Algorithm::Md5 => Box::new(md5::Md5::default()),
+            Algorithm::Sha1 => Box::new(sha1::Sha1::default()),
+            Algorithm::Sha256 => Box::new(sha2::Sha256::default()),
+            Algorithm::Sha512 => Box::new(sha2::Sha512::default()),
+        }
+    }
+
+    /// The algorithm's name as written on the command line and in structured output, e.g. `sha256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Resistance to collisions, in bits: the base-two logarithm of the work needed to find two
+    /// inputs with the same digest. For MD5 and SHA-1, this is the cost of the best known attack.
+    pub fn strength(self) -> u32 {
+        match self {
+            Algorithm::Md5 => 18,
+            Algorithm::Sha1 => 63,
+            Algorithm::Blake3 | Algorithm::Sha256 => 128,
+            Algorithm::Sha512 => 256,
+        }
+    }
+
+    /// Whether FIPS 180-4 specifies this algorithm and NIST approves it for new work.
+    pub fn is_fips_approved(self) -> bool {
+        matches!(self, Algorithm::Sha256 | Algorithm::Sha512)
+    }
+
+    /// The hardware acceleration the implementation of this algorithm uses on this machine, if
+    /// any, e.g. `AVX2` or `SHA-NI`.
+    pub fn acceleration(self) -> Option<&'static str> {
+        sys::acceleration(self)
+    }
+
+    #[inline]
+    pub fn hash_uncached(self, path: &Path) -> io::Result<String> {
         match self {
-            Algorithm::Blake3 => crate::hash::hash_to_string(path, blake3::Hasher::new()),
+            Algorithm::Blake3 => crate::hash::hash_blake3(path),
             Algorithm::Md5 => crate::hash::hash_to_string(path, md5::Md5::default()),
             Algorithm::Sha1 => crate::hash::hash_to_string(path, sha1::Sha1::default()),
             Algorithm::Sha256 => crate::hash::hash_to_string(path, sha2::Sha256::default()),
@@ -40,7 +103,13 @@ impl FromStr for Algorithm {
     type Err = UnknownAlgorithmError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_ascii_uppercase().trim_end_matches("SUM").as_ref() {
+        let s_upper = s.to_ascii_uppercase();
+        let name = s_upper
+            .strip_suffix("SUMS")
+            .or_else(|| s_upper.strip_suffix("SUM"))
+            .unwrap_or(&s_upper);
+
+        match name {
             "BLAKE3" => Ok(Algorithm::Blake3),
             "MD5" => Ok(Algorithm::Md5),
             "SHA1" => Ok(Algorithm::Sha1),
@@ -61,3 +130,66 @@ impl fmt::Display for UnknownAlgorithmError {
 }
 
 impl std::error::Error for UnknownAlgorithmError {}
+
+/// The instructions each algorithm's implementation chooses at run time on x86, as the blake3,
+/// sha-1 and sha2 crates do.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sys {
+    use std::arch::is_x86_feature_detected as has;
+
+    use super::Algorithm;
+
+    pub fn acceleration(algorithm: Algorithm) -> Option<&'static str> {
+        let sha_ni = has!("sha") && has!("sse2") && has!("ssse3") && has!("sse4.1");
+        match algorithm {
+            Algorithm::Blake3 if has!("avx512f") && has!("avx512vl") => Some("AVX-512"),
+            Algorithm::Blake3 if has!("avx2") => Some("AVX2"),
+            Algorithm::Blake3 if has!("sse4.1") => Some("SSE4.1"),
+            Algorithm::Blake3 if has!("sse2") => Some("SSE2"),
+            Algorithm::Sha1 | Algorithm::Sha256 if sha_ni => Some("SHA-NI"),
+            Algorithm::Sha512 if has!("avx2") => Some("AVX2"),
+            _ => None,
+        }
+    }
+}
+
+// On ARM, blake3 always uses NEON, while the sha-1 and sha2 crates use the SHA extensions only
+// when built with their asm features, which we don't enable.
+#[cfg(target_arch = "aarch64")]
+mod sys {
+    use super::Algorithm;
+
+    pub fn acceleration(algorithm: Algorithm) -> Option<&'static str> {
+        match algorithm {
+            Algorithm::Blake3 => Some("NEON"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+mod sys {
+    use super::Algorithm;
+
+    pub fn acceleration(_algorithm: Algorithm) -> Option<&'static str> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Algorithm;
+
+    #[test]
+    fn names_parse_with_or_without_sums_suffix() {
+        let parse = |s: &str| s.parse::<Algorithm>().ok();
+
+        assert_eq!(Some(Algorithm::Sha256), parse("sha256"));
+        assert_eq!(Some(Algorithm::Sha256), parse("SHA256SUMS"));
+        assert_eq!(Some(Algorithm::Md5), parse("md5sum"));
+        assert_eq!(Some(Algorithm::Blake3), parse("BLAKE3SUMS"));
+        assert_eq!(Some(Algorithm::Sha1), parse("Sha1Sum"));
+        assert_eq!(None, parse("SUMS"));
+        assert_eq!(None, parse("sha3SUMS"));
+    }
+}
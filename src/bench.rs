@@ -0,0 +1,170 @@
+//! Measuring how fast each algorithm hashes on this machine.
+//!
+//! Each algorithm hashes the same data held in memory, first on one thread and then on every
+//! thread at once, as when hashing many files. A file may also be hashed with each algorithm, to
+//! show what the storage behind it allows: streamed through the double-buffered reader, and for
+//! blake3, through a memory mapping as well, as checksum hashes large files.
+
+use std::{
+    fs::{File, Metadata},
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use rayon::prelude::*;
+
+use crate::{
+    alg::Algorithm,
+    disk,
+    error::{Context, Operation},
+    hash::{self, ByteSize},
+};
+
+/// How long to spend on each measurement of data in memory.
+const MEASURE_TIME: Duration = Duration::from_secs(1);
+
+pub fn bench(path: Option<&Path>, size: ByteSize) -> crate::Result<()> {
+    let data = test_data(size.bytes());
+    let threads = rayon::current_num_threads();
+
+    // Read the file once first, so that every algorithm finds as much of it cached as the last.
+    if let Some(path) = path {
+        let file = File::open(path).context(Operation::Open, path)?;
+        hash::read_chunks(file, |_| {}).context(Operation::Read, path)?;
+    }
+
+    let parallel = match threads {
+        1 => "1 thread".to_string(),
+        n => format!("{n} threads"),
+    };
+    let mut header = format!(
+        "{:<10}{:<14}{:>14}{:>14}",
+        "algorithm", "acceleration", "1 thread", parallel
+    );
+    if path.is_some() {
+        header.push_str(&format!("{:>14}{:>14}", "streamed", "mapped"));
+    }
+    println!("{header}");
+
+    for algorithm in Algorithm::ALL {
+        let single = measure(MEASURE_TIME, || hash_data(algorithm, &data));
+        let parallel = measure(MEASURE_TIME, || {
+            (0..threads)
+                .into_par_iter()
+                .map(|_| hash_data(algorithm, &data))
+                .sum()
+        });
+
+        let name = algorithm.name();
+        let acceleration = algorithm.acceleration().unwrap_or("none");
+        print!(
+            "{name:<10}{acceleration:<14}{:>14}{:>14}",
+            Throughput(single),
+            Throughput(parallel)
+        );
+
+        match path {
+            Some(path) => {
+                let streamed = time_file(path, |file, _| {
+                    let mut digest = algorithm.digest();
+                    hash::read_chunks(file, |chunk| digest.update(chunk))?;
+                    let _ = digest.finalize();
+                    Ok(())
+                })?;
+                print!("{:>14}", Throughput(streamed));
+
+                // Only blake3 hashes files through a mapping, and an empty file can't be mapped.
+                let len = path.metadata().context(Operation::Read, path)?.len();
+                if algorithm == Algorithm::Blake3 && len > 0 {
+                    let mapped = time_file(path, |file, meta| {
+                        let parallel = disk::spindle(meta).is_none();
+                        hash::hash_mapped(&file, meta.len(), parallel)?
+                            .map(drop)
+                            .ok_or_else(|| io::Error::other("file changed while being hashed"))
+                    })?;
+                    println!("{:>14}", Throughput(mapped));
+                } else {
+                    println!("{:>14}", "-");
+                }
+            }
+            None => println!(),
+        }
+    }
+
+    Ok(())
+}
+
+/// Time `f` hashing the file at `path`, returning the bytes it hashed per second.
+fn time_file(path: &Path, f: impl FnOnce(File, &Metadata) -> io::Result<()>) -> crate::Result<f64> {
+    let file = File::open(path).context(Operation::Open, path)?;
+    let meta = file.metadata().context(Operation::Read, path)?;
+
+    let start = Instant::now();
+    f(file, &meta).context(Operation::Read, path)?;
+    Ok(meta.len() as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Hash `data` with `algorithm`, returning the number of bytes hashed.
+fn hash_data(algorithm: Algorithm, data: &[u8]) -> u64 {
+    let mut digest = algorithm.digest();
+    digest.update(data);
+    let _ = digest.finalize();
+    data.len() as u64
+}
+
+/// Run `f` repeatedly for at least `time`, returning the bytes it hashed per second.
+fn measure(time: Duration, f: impl Fn() -> u64) -> f64 {
+    let start = Instant::now();
+    let mut bytes = 0;
+    while start.elapsed() < time || bytes == 0 {
+        bytes += f();
+    }
+    bytes as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Data which no algorithm could take a shortcut through.
+fn test_data(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Bytes per second, written in megabytes per second.
+struct Throughput(f64);
+
+impl std::fmt::Display for Throughput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = format!("{:.1} MB/s", self.0 / 1e6);
+        f.pad(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{hash_data, measure, test_data, Throughput};
+    use crate::alg::Algorithm;
+
+    #[test]
+    fn measurement_counts_every_pass() {
+        let data = test_data(4096);
+        let rate = measure(Duration::ZERO, || hash_data(Algorithm::Md5, &data));
+        assert!(rate > 0.0);
+    }
+
+    #[test]
+    fn throughput_is_padded() {
+        assert_eq!(
+            "  1234.6 MB/s",
+            format!("{:>13}", Throughput(1_234_567_890.0))
+        );
+    }
+}
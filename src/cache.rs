@@ -0,0 +1,453 @@
+//! A persistent cache of file digests.
+//!
+//! Entries are keyed by algorithm, device and inode, and are considered fresh only so long as the
+//! file's size, mtime and ctime are unchanged. The cache file is an append-only log: each newly
+//! computed digest is written through immediately, so nothing is lost if the process exits early,
+//! and later lines supersede earlier ones. The log is compacted when it is loaded.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hashbrown::HashMap;
+
+use crate::alg::Algorithm;
+
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+/// Set when `--verify-cache` finds a file whose content no longer matches its cached digest
+/// even though its metadata is unchanged.
+static BIT_ROT: AtomicBool = AtomicBool::new(false);
+
+static HEADER: &str = "# checksum cache v1";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CachePolicy {
+    /// Ignore cached digests, but record freshly computed ones.
+    pub no_lookup: bool,
+    /// Re-read files with fresh cache entries and report any whose content has changed.
+    pub verify: bool,
+}
+
+/// Load the cache at `path` and install it for the remainder of the process.
+pub fn init(path: &Path, policy: CachePolicy) -> io::Result<()> {
+    let cache = Cache::open(path, policy)?;
+    let _ = CACHE.set(cache);
+    Ok(())
+}
+
+pub fn get() -> Option<&'static Cache> {
+    CACHE.get()
+}
+
+pub fn bit_rot_detected() -> bool {
+    BIT_ROT.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    algorithm: Algorithm,
+    dev: u64,
+    ino: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    mtime_ns: i128,
+    ctime_ns: i128,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    stamp: Stamp,
+    hash: String,
+}
+
+pub struct Cache {
+    policy: CachePolicy,
+    entries: Mutex<HashMap<Key, Entry>>,
+    log: Mutex<BufWriter<File>>,
+}
+
+impl Cache {
+    fn open(path: &Path, policy: CachePolicy) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let (entries, lines) = match File::open(path) {
+            Ok(file) => read_entries(file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e),
+        };
+
+        // Compact the log whenever superseded lines make up the bulk of it, and always when
+        // creating it, so that the header is written.
+        if lines == 0 || lines > entries.len() * 2 {
+            write_entries(path, &entries)?;
+        }
+
+        let log = OpenOptions::new().append(true).open(path)?;
+
+        Ok(Self {
+            policy,
+            entries: Mutex::new(entries),
+            log: Mutex::new(BufWriter::new(log)),
+        })
+    }
+
+    /// Hash `path` with `algorithm`, consulting and updating the cache.
+    ///
+    /// `hash` is called to compute the digest whenever there is no fresh entry for the file,
+    /// or when the policy requires the file to be re-read.
+    pub fn hash(
+        &self,
+        algorithm: Algorithm,
+        path: &Path,
+        hash: impl FnOnce(&Path) -> io::Result<String>,
+    ) -> io::Result<String> {
+        let meta = fs::metadata(path)?;
+        let Some((key, stamp)) = key_for(algorithm, &meta) else {
+            return hash(path);
+        };
+
+        match self.fresh(key, stamp) {
+            Some(cached) if self.policy.verify => {
+                let actual = hash(path)?;
+                if !cached.eq_ignore_ascii_case(&actual) {
+                    // The cached digest is the last known good value, so we leave it in place
+                    // and let every subsequent verification flag the file as well.
+                    BIT_ROT.store(true, Ordering::Relaxed);
+                    eprintln!("bit rot detected: {}", path.display());
+                }
+                Ok(actual)
+            }
+            Some(cached) if !self.policy.no_lookup => {
+                crate::progress::add_bytes(meta.len());
+                Ok(cached)
+            }
+            _ => {
+                let actual = hash(path)?;
+
+                // If the file changed while we were reading it, the digest we computed may not
+                // correspond to either version. Return it, but don't remember it.
+                let after = fs::metadata(path)?;
+                if key_for(algorithm, &after) == Some((key, stamp)) {
+                    self.insert(
+                        key,
+                        Entry {
+                            stamp,
+                            hash: actual.clone(),
+                        },
+                    )?;
+                }
+
+                Ok(actual)
+            }
+        }
+    }
+
+    /// The digest of `path` with `algorithm`, if the cache holds a fresh one and we may use it.
+    /// Nothing is read or hashed.
+    pub fn lookup(&self, algorithm: Algorithm, path: &Path) -> Option<String> {
+        if self.policy.no_lookup || self.policy.verify {
+            return None;
+        }
+
+        let meta = fs::metadata(path).ok()?;
+        let (key, stamp) = key_for(algorithm, &meta)?;
+        self.fresh(key, stamp)
+    }
+
+    fn fresh(&self, key: Key, stamp: Stamp) -> Option<String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|entry| entry.stamp == stamp)
+            .map(|entry| entry.hash.clone())
+    }
+
+    fn insert(&self, key: Key, entry: Entry) -> io::Result<()> {
+        {
+            let mut log = self.log.lock().unwrap();
+            write_entry(&mut *log, &key, &entry)?;
+            log.flush()?;
+        }
+
+        self.entries.lock().unwrap().insert(key, entry);
+        Ok(())
+    }
+}
+
+/// Default location of the cache file, per the XDG base directory spec.
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+    Some(base.join("checksum").join("hashes"))
+}
+
+#[cfg(unix)]
+fn key_for(algorithm: Algorithm, meta: &fs::Metadata) -> Option<(Key, Stamp)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let nanos = |secs: i64, nsec: i64| i128::from(secs) * 1_000_000_000 + i128::from(nsec);
+    let key = Key {
+        algorithm,
+        dev: meta.dev(),
+        ino: meta.ino(),
+    };
+    let stamp = Stamp {
+        size: meta.size(),
+        mtime_ns: nanos(meta.mtime(), meta.mtime_nsec()),
+        ctime_ns: nanos(meta.ctime(), meta.ctime_nsec()),
+    };
+
+    Some((key, stamp))
+}
+
+// Without a stable file identity there is nothing safe to key on, so the cache is inert.
+#[cfg(not(unix))]
+fn key_for(_algorithm: Algorithm, _meta: &fs::Metadata) -> Option<(Key, Stamp)> {
+    None
+}
+
+fn read_entries(file: File) -> io::Result<(HashMap<Key, Entry>, usize)> {
+    let mut entries = HashMap::new();
+    let mut lines = 0;
+    let mut reader = BufReader::new(file).lines();
+
+    // Anything but a cache, or an empty file, is somebody else's, and not ours to rewrite.
+    match reader.next().transpose()? {
+        None => return Ok((entries, lines)),
+        Some(header) if header == HEADER => {}
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a checksum cache",
+            ))
+        }
+    }
+
+    for line in reader {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+
+        lines += 1;
+
+        // A torn or otherwise unreadable line costs us one cache entry, nothing more.
+        if let Some((key, entry)) = parse_entry(&line) {
+            entries.insert(key, entry);
+        }
+    }
+
+    Ok((entries, lines))
+}
+
+fn parse_entry(line: &str) -> Option<(Key, Entry)> {
+    let mut fields = line.split('\t');
+    let algorithm = fields.next()?.parse().ok()?;
+    let dev = fields.next()?.parse().ok()?;
+    let ino = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let mtime_ns = fields.next()?.parse().ok()?;
+    let ctime_ns = fields.next()?.parse().ok()?;
+    let hash = fields.next()?.into();
+
+    if fields.next().is_some() {
+        return None;
+    }
+
+    let key = Key {
+        algorithm,
+        dev,
+        ino,
+    };
+    let stamp = Stamp {
+        size,
+        mtime_ns,
+        ctime_ns,
+    };
+
+    Some((key, Entry { stamp, hash }))
+}
+
+fn write_entry(mut out: impl Write, key: &Key, entry: &Entry) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        key.algorithm,
+        key.dev,
+        key.ino,
+        entry.stamp.size,
+        entry.stamp.mtime_ns,
+        entry.stamp.ctime_ns,
+        entry.hash,
+    )
+}
+
+fn write_entries(path: &Path, entries: &HashMap<Key, Entry>) -> io::Result<()> {
+    rewrite(path, |out| {
+        writeln!(out, "{HEADER}")?;
+        for (key, entry) in entries {
+            write_entry(&mut *out, key, entry)?;
+        }
+        Ok(())
+    })
+}
+
+/// Replace the file at `path` with whatever `write` writes, by way of a temporary file beside it,
+/// so that the file is never seen half-written. Append-only logs such as this cache and the
+/// state kept by `checksum scrub` are compacted this way.
+pub fn rewrite(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let (temp, file) = create_temp(path)?;
+
+    let written = (|| {
+        let mut out = BufWriter::new(file);
+        write(&mut out)?;
+        out.flush()?;
+        fs::rename(&temp, path)
+    })();
+
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Create a file beside `path` under a name no other run will choose, so that two runs
+/// compacting the same log at once can't write into each other's files.
+fn create_temp(path: &Path) -> io::Result<(PathBuf, File)> {
+    loop {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".{}.{nanos:09}.tmp", std::process::id()));
+
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((temp.into(), file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use super::{
+        create_temp, parse_entry, write_entry, Cache, CachePolicy, Entry, Key, Stamp, HEADER,
+    };
+    use crate::{alg::Algorithm, temp::TempDir};
+
+    #[cfg(unix)]
+    #[test]
+    fn fresh_digests_are_found_without_reading() {
+        let temp = TempDir::new("lookup").unwrap();
+        let dir = temp.path();
+        let file = dir.join("file");
+        fs::write(&file, "contents").unwrap();
+
+        let cache = Cache::open(&dir.join("hashes"), CachePolicy::default()).unwrap();
+        assert_eq!(None, cache.lookup(Algorithm::Sha256, &file));
+
+        let hash = cache
+            .hash(Algorithm::Sha256, &file, |_| Ok("abc123".into()))
+            .unwrap();
+        assert_eq!("abc123", hash);
+        assert_eq!(Some(hash), cache.lookup(Algorithm::Sha256, &file));
+        assert_eq!(None, cache.lookup(Algorithm::Blake3, &file));
+
+        // A digest we'd re-read the file for under the policy is no use without reading.
+        let policy = CachePolicy {
+            verify: true,
+            ..Default::default()
+        };
+        let verifying = Cache::open(&dir.join("hashes"), policy).unwrap();
+        assert_eq!(None, verifying.lookup(Algorithm::Sha256, &file));
+
+        fs::write(&file, "changed contents").unwrap();
+        assert_eq!(None, cache.lookup(Algorithm::Sha256, &file));
+    }
+
+    #[test]
+    fn other_files_are_refused_and_left_alone() {
+        let temp = TempDir::new("foreign").unwrap();
+        let notes = temp.path().join("notes.txt");
+        fs::write(&notes, "remember the milk\n").unwrap();
+
+        let error = Cache::open(&notes, CachePolicy::default()).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert_eq!("remember the milk\n", fs::read_to_string(&notes).unwrap());
+
+        // An empty file holds nothing to lose, and becomes a cache.
+        let empty = temp.path().join("empty");
+        fs::write(&empty, "").unwrap();
+        Cache::open(&empty, CachePolicy::default()).unwrap();
+        assert!(fs::read_to_string(&empty).unwrap().starts_with(HEADER));
+    }
+
+    #[test]
+    fn temp_files_are_never_shared() {
+        let temp = TempDir::new("temp").unwrap();
+        let dir = temp.path();
+
+        let path = dir.join("hashes");
+        let (first, _) = create_temp(&path).unwrap();
+        let (second, _) = create_temp(&path).unwrap();
+
+        assert_ne!(first, second);
+        assert!(first.starts_with(dir) && second.starts_with(dir));
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let key = Key {
+            algorithm: Algorithm::Sha256,
+            dev: 2049,
+            ino: 1234567,
+        };
+        let entry = Entry {
+            stamp: Stamp {
+                size: 42,
+                mtime_ns: 1_700_000_000_123_456_789,
+                ctime_ns: -5,
+            },
+            hash: "abc123".into(),
+        };
+
+        let mut buf = Vec::new();
+        write_entry(&mut buf, &key, &entry).unwrap();
+        let line = String::from_utf8(buf).unwrap();
+        let (parsed_key, parsed_entry) = parse_entry(line.trim_end()).unwrap();
+
+        assert_eq!(key, parsed_key);
+        assert_eq!(entry.stamp, parsed_entry.stamp);
+        assert_eq!(entry.hash, parsed_entry.hash);
+    }
+
+    #[test]
+    fn malformed_entries_are_ignored() {
+        assert!(parse_entry("Sha256\t1\t2\t3").is_none());
+        assert!(parse_entry("Nope\t1\t2\t3\t4\t5\tabc").is_none());
+        assert!(parse_entry("Sha256\t1\t2\t3\t4\t5\tabc\textra").is_none());
+    }
+}
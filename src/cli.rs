@@ -1,11 +1,24 @@
-use std::path::Path;
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process,
+};
 
-use clap::{ArgGroup, Parser, Subcommand};
+use clap::{
+    builder::BoolishValueParser, parser::ValueSource, ArgGroup, CommandFactory, FromArgMatches,
+    Parser, Subcommand,
+};
 
 use crate::{
     alg::Algorithm,
-    error::{Error, OperationKind},
-    CHECKSUM_DEFAULT_ALG,
+    cache::{self, CachePolicy},
+    error::{Error, OperationKind, EXIT_USAGE},
+    hash::ByteSize,
+    hk::ReadPolicy,
+    progress::ProgressFormat,
+    scrub::{Budget, Fraction, Limit},
+    walk::{SymlinkPolicy, WalkOptions},
+    CHECKSUM_DEFAULT_ALG, CHECKSUM_FIPS, CHECKSUM_MIN_STRENGTH,
 };
 
 /// a checksum command
@@ -21,11 +34,16 @@ pub struct Args {
     #[arg(required = true)]
     pub target: Option<String>,
 
-    /// a file or directory
+    /// a file, directory, or sum file
     ///
     /// Provide this argument to assert that the target and comparison targets
     /// are equal. Non-equal files will be printed to the screen. Any non-equal
     /// files will cause the command to return an error code to the shell.
+    ///
+    /// If the target is a directory and the comparison target is a sum file,
+    /// the directory is audited against the sum file: files that are listed
+    /// but missing, files that do not match, and files that are present but
+    /// unlisted are all reported.
     #[arg(short, long, group("compare to"))]
     pub compare: Option<String>,
 
@@ -41,10 +59,38 @@ pub struct Args {
     /// by setting an environment variable called CHECKSUM_DEFAULT_ALG. Must be one of
     /// blake3, md5, sha1, sha256, sha512.
     ///
-    /// For internal comparisons, checksum uses Blake3.
-    #[arg(short, long, env(CHECKSUM_DEFAULT_ALG))]
+    /// For internal comparisons, checksum uses Blake3, or sha256 in FIPS mode. In FIPS
+    /// mode, an unapproved CHECKSUM_DEFAULT_ALG gives way to sha256.
+    #[arg(short, long, env(CHECKSUM_DEFAULT_ALG), global(true))]
     mode: Option<Algorithm>,
 
+    /// Whether the algorithm was taken from the environment rather than the command line.
+    #[arg(skip)]
+    mode_from_env: bool,
+
+    /// refuse algorithms with fewer bits of collision resistance than this
+    ///
+    /// Applies to printing, asserting, verifying sum files and extended
+    /// attributes. Collision resistance is md5 18, sha1 63, blake3 128, sha256
+    /// 128 and sha512 256, so 128 refuses md5 and sha1. A refusal returns an
+    /// error code (4) to the shell. May also be set by an environment variable
+    /// called CHECKSUM_MIN_STRENGTH.
+    #[arg(long, value_name = "BITS", env(CHECKSUM_MIN_STRENGTH), global(true))]
+    pub min_strength: Option<u32>,
+
+    /// warn about algorithms below --min-strength rather than refusing them
+    #[arg(long, requires("min_strength"), global(true))]
+    pub warn_weak: bool,
+
+    /// allow only FIPS-approved algorithms (sha256 and sha512)
+    ///
+    /// Any other algorithm named with --mode is refused, sha256 is the default
+    /// everywhere, internal comparisons use sha256 rather than Blake3, and
+    /// directory trees are always compared in full. May also be set by an
+    /// environment variable called CHECKSUM_FIPS.
+    #[arg(long, env(CHECKSUM_FIPS), value_parser = BoolishValueParser::new(), global(true))]
+    pub fips: bool,
+
     /// force full comparison
     ///
     /// Comparisons between directory trees are partial comparisons
@@ -54,16 +100,184 @@ pub struct Args {
     pub force_full_compare: bool,
 
     /// print names of matching files during directory comparisons
-    #[arg(short, long)]
+    #[arg(short, long, global(true))]
     pub verbose: bool,
 
+    /// only visit files matching this pattern
+    ///
+    /// Patterns use gitignore syntax and are matched against paths relative to
+    /// the directory being walked. May be given more than once.
+    #[arg(long, value_name = "PATTERN", global(true))]
+    include: Vec<String>,
+
+    /// skip files and directories matching this pattern
+    ///
+    /// Patterns use gitignore syntax and are matched against paths relative to
+    /// the directory being walked. May be given more than once. Patterns may
+    /// also be kept in a .checksumignore file in any directory.
+    #[arg(long, value_name = "PATTERN", global(true))]
+    exclude: Vec<String>,
+
+    /// skip files ignored by .gitignore files
+    #[arg(long, global(true))]
+    respect_gitignore: bool,
+
+    /// include files whose names begin with a dot
+    #[arg(long, global(true))]
+    hidden: bool,
+
+    /// skip directories whose names begin with a dot
+    ///
+    /// By default, checksum descends into hidden directories even though it
+    /// skips hidden files.
+    #[arg(long, global(true))]
+    no_hidden_dirs: bool,
+
+    /// how to treat symbolic links: follow, skip or compare-target
+    ///
+    /// Links are skipped by default. When following links, links which would
+    /// lead outside the directory being walked are reported rather than
+    /// followed, as are broken links and link loops. When comparing targets,
+    /// two directory trees match only if their links point at the same
+    /// targets; links are otherwise skipped.
+    #[arg(long, value_name = "POLICY", default_value_t, global(true))]
+    symlinks: SymlinkPolicy,
+
+    /// report unreadable files and directories as warnings
+    ///
+    /// By default, any part of a directory tree which cannot be read is
+    /// reported as an error and causes the command to return a distinct error
+    /// code (3) to the shell.
+    #[arg(long, global(true))]
+    skip_errors: bool,
+
+    /// stop at the first file which cannot be read
+    ///
+    /// By default, operations on many files report any file which cannot be
+    /// read, carry on with the rest, and return an error code (3) to the shell
+    /// at the end.
+    #[arg(long, global(true))]
+    pub fail_fast: bool,
+
+    /// warn about improperly formatted lines in sum files and carry on
+    ///
+    /// By default, a sum file containing any line which is neither a comment,
+    /// a blank line nor a valid entry is rejected, and the command returns an
+    /// error code (5) to the shell.
+    #[arg(long, global(true))]
+    lenient: bool,
+
+    /// accept sum file entries naming files outside the sum file's directory
+    ///
+    /// By default, absolute names and names which climb out of the directory
+    /// containing the sum file (e.g. ../../etc/passwd) are rejected, so that a
+    /// downloaded sum file can't be used to probe other files.
+    #[arg(long, global(true))]
+    allow_outside: bool,
+
+    /// size of each of the two buffers used to read files (e.g. 256K, 4M)
+    ///
+    /// Files are read on one thread while being hashed on another. Larger
+    /// buffers mean fewer, larger reads. The default is 1M, and the most is 1G.
+    #[arg(long, value_name = "SIZE", global(true))]
+    pub buffer_size: Option<ByteSize>,
+
+    /// drop file contents from the page cache after hashing them
+    ///
+    /// Hashing a large archive would otherwise evict everything else from the
+    /// page cache. This has no effect on platforms which can't be told to drop
+    /// cached file contents.
+    #[arg(long, global(true))]
+    pub no_cache_pollution: bool,
+
+    /// read files no faster than this many bytes per second (e.g. 200M)
+    ///
+    /// The limit applies to all files being read at once, taken together.
+    /// Directory trees are compared in full while a limit is in force, since
+    /// the reads made by the quick comparison can't be metered.
+    #[arg(long, value_name = "RATE", global(true))]
+    pub rate_limit: Option<ByteSize>,
+
+    /// run at the lowest CPU and I/O priority
+    ///
+    /// Sets a niceness of 19 and, on Linux, the idle I/O scheduling class, so
+    /// that checksum reads from a disk only when nothing else wants it. Not
+    /// every I/O scheduler honors the idle class.
+    #[arg(long, global(true))]
+    pub nice: bool,
+
+    /// report progress on stderr as a bar or as json
+    ///
+    /// The bar shows bytes read, throughput, files done and the time left, and
+    /// is drawn only when stderr is a terminal. With --progress=json, an event
+    /// is written to stderr each second, and once more at the end. Applies to
+    /// printing, verifying and comparing.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("bar"),
+        global(true)
+    )]
+    pub progress: Option<ProgressFormat>,
+
+    /// cache digests between runs
+    ///
+    /// Digests are cached per algorithm and keyed on each file's device, inode,
+    /// size, mtime and ctime; any change to these invalidates the cached digest.
+    /// If no file is given, the cache is kept in the user's cache directory
+    /// (e.g. ~/.cache/checksum/hashes). A file which isn't a cache is refused.
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args(0..=1),
+        require_equals(true),
+        global(true)
+    )]
+    cache: Option<Option<PathBuf>>,
+
+    /// ignore cached digests and re-read every file
+    ///
+    /// Freshly computed digests are still written to the cache.
+    #[arg(long, requires("cache"), global(true))]
+    no_cache: bool,
+
+    /// re-read files with cached digests and report any that have changed
+    ///
+    /// A file whose content no longer matches its cached digest even though its
+    /// metadata is unchanged has suffered bit rot. Any such file will cause the
+    /// command to return an error code to the shell.
+    #[arg(long, requires("cache"), conflicts_with("no_cache"), global(true))]
+    verify_cache: bool,
+
     #[command(subcommand)]
     pub command: Option<Command>,
 }
 
 impl Args {
     pub fn parse() -> Self {
-        Parser::parse()
+        Self::parse_with(Self::command(), std::env::args_os()).unwrap_or_else(|e| {
+            // Requests for help or version information arrive as errors, but aren't failures.
+            if !e.use_stderr() {
+                e.exit();
+            }
+
+            let _ = e.print();
+            process::exit(EXIT_USAGE)
+        })
+    }
+
+    /// Parse `args` with `command`, noting whether the algorithm was taken from the environment.
+    fn parse_with<I, T>(command: clap::Command, args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = command.try_get_matches_from(args)?;
+        let mut args = Self::from_arg_matches(&matches)?;
+        args.mode_from_env = matches.value_source("mode") == Some(ValueSource::EnvVariable);
+        Ok(args)
     }
 
     pub fn target(&self) -> &str {
@@ -73,7 +287,48 @@ impl Args {
     }
 
     pub fn mode(&self) -> Algorithm {
-        self.mode.unwrap_or_default()
+        match self.mode {
+            // In FIPS mode, the default is sha256 everywhere, whatever the environment says.
+            Some(mode) if self.mode_from_env && self.fips && !mode.is_fips_approved() => {
+                Algorithm::default()
+            }
+            Some(mode) => mode,
+            None => Algorithm::default(),
+        }
+    }
+
+    pub fn walk_options(&self) -> WalkOptions {
+        WalkOptions {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            respect_gitignore: self.respect_gitignore,
+            hidden_files: self.hidden,
+            hidden_dirs: !self.no_hidden_dirs,
+            symlinks: self.symlinks,
+            skip_errors: self.skip_errors,
+        }
+    }
+
+    pub fn cache_path(&self) -> crate::Result<Option<PathBuf>> {
+        match &self.cache {
+            None => Ok(None),
+            Some(Some(path)) => Ok(Some(path.clone())),
+            Some(None) => cache::default_path().map(Some).ok_or(Error::CacheDir),
+        }
+    }
+
+    pub fn cache_policy(&self) -> CachePolicy {
+        CachePolicy {
+            no_lookup: self.no_cache,
+            verify: self.verify_cache,
+        }
+    }
+
+    pub fn read_policy(&self) -> ReadPolicy {
+        ReadPolicy {
+            lenient: self.lenient,
+            allow_outside: self.allow_outside,
+        }
     }
 
     pub fn validate(&self) -> crate::Result<()> {
@@ -88,7 +343,7 @@ impl Args {
         let left: &Path = target.as_ref();
         let right: &Path = compare.as_ref();
 
-        if left.is_dir() && !right.is_dir() {
+        if left.is_dir() && !right.is_dir() && !right.is_file() {
             return Err(Error::InvalidOperation(OperationKind::Dir));
         }
 
@@ -99,9 +354,143 @@ impl Args {
 #[derive(Clone, Debug, Subcommand)]
 pub enum Command {
     File(FileCommand),
+    /// compare two sum files
+    ///
+    /// Reports entries that were added, removed, changed, or renamed (same hash,
+    /// new name) between the old and new sum files. Both sum files must use the
+    /// same algorithm. Any difference will cause the command to return an error
+    /// code to the shell.
+    Diff(DiffCommand),
+    /// store or verify checksums in extended attributes
+    #[command(subcommand)]
+    Xattr(XattrCommand),
+    /// verify part of a sum file, least recently verified entries first
+    ///
+    /// The time at which each entry was last verified is kept in a state file
+    /// beside the sum file, so that successive scrubs work their way through
+    /// the whole of it. Any missing or corrupt file will cause the command to
+    /// return an error code to the shell.
+    Scrub(ScrubCommand),
+    /// measure how fast each algorithm hashes on this machine
+    ///
+    /// Each algorithm hashes data held in memory, first on one thread and then
+    /// on every thread at once, and then, if one is given, a file: streamed, and
+    /// for blake3, memory-mapped as well. Throughput is reported in megabytes
+    /// per second, alongside any hardware acceleration the algorithm is using.
+    Bench(BenchCommand),
+    /// check each algorithm against published known answers
+    ///
+    /// Each vector is hashed in memory, in pieces, from a file, in a batch of
+    /// files and, for blake3, through a memory mapping on one core and on all of
+    /// them. The hardware acceleration each algorithm uses is printed beside its
+    /// result. Any discrepancy will cause the command to return an error code to
+    /// the shell.
+    Selftest,
 }
 
 #[derive(Clone, Debug, Parser)]
 pub struct FileCommand {
     pub path: String,
 }
+
+#[derive(Clone, Debug, Parser)]
+pub struct DiffCommand {
+    /// the old sum file
+    pub old: String,
+
+    /// the new sum file
+    pub new: String,
+
+    /// print differences as json
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum XattrCommand {
+    /// hash each file and store the result in its extended attributes
+    ///
+    /// Checksums are stored as user.checksum.<alg>, alongside the file's mtime
+    /// at the time it was hashed with that algorithm. Sha256 checksums are also stored in the form
+    /// used by shatag.
+    Write(XattrPath),
+    /// verify each file against the checksum in its extended attributes
+    ///
+    /// Files whose content has changed along with their mtime are reported as
+    /// modified. Files whose content has changed without a change in mtime are
+    /// reported as corrupt and will cause the command to return an error code
+    /// to the shell.
+    Verify(XattrPath),
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct XattrPath {
+    /// a file or directory
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ScrubCommand {
+    /// a sum file
+    pub path: String,
+
+    /// stop starting new files after this long, e.g. 45m or 2h
+    #[arg(long)]
+    pub budget: Option<Budget>,
+
+    /// verify at most this share of the sum file, e.g. 10%
+    #[arg(long)]
+    pub fraction: Option<Fraction>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct BenchCommand {
+    /// a file to hash with each algorithm
+    pub path: Option<String>,
+
+    /// amount of data to hold in memory and hash, e.g. 64M
+    #[arg(long, default_value = "64M")]
+    pub size: ByteSize,
+}
+
+impl ScrubCommand {
+    pub fn limit(&self) -> Limit {
+        Limit {
+            budget: self.budget,
+            fraction: self.fraction,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::Args;
+    use crate::alg::Algorithm;
+
+    /// Parse `args`, taking the algorithm from `env` where the command line doesn't give one.
+    fn parse(args: &[&str], env: &str) -> Args {
+        // A variable of our own, so as not to disturb anything else reading the real one.
+        std::env::set_var("CHECKSUM_TEST_MODE", env);
+        let command = Args::command().mut_arg("mode", |arg| arg.env("CHECKSUM_TEST_MODE"));
+        Args::parse_with(command, [&["checksum"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn fips_mode_defaults_to_sha256_whatever_the_environment_says() {
+        assert_eq!(Algorithm::Blake3, parse(&["a.txt"], "blake3").mode());
+        assert_eq!(
+            Algorithm::Sha256,
+            parse(&["--fips", "a.txt"], "blake3").mode()
+        );
+        assert_eq!(
+            Algorithm::Sha512,
+            parse(&["--fips", "a.txt"], "sha512").mode()
+        );
+
+        // An algorithm named on the command line is left for the policy to refuse.
+        let args = parse(&["--fips", "-m", "md5", "a.txt"], "md5");
+        assert_eq!(Algorithm::Md5, args.mode());
+    }
+}
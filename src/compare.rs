@@ -1,4 +1,5 @@
 use std::{
+    fs,
     io::{self, IsTerminal},
     path::{Path, PathBuf},
 };
@@ -8,24 +9,27 @@ use imprint::Imprint;
 use owo_colors::OwoColorize;
 use rayon::prelude::*;
 
-use crate::iter::IsUniform;
+use crate::{alg::Algorithm, fmt::DisplayPath, iter::IsUniform};
 
 pub trait Comparer {
     type Output: Eq;
     fn build(path: &Path) -> io::Result<Self::Output>;
+
+    /// Decide whether two files match without reading either, if we can.
+    fn settle(_left: &Path, _right: &Path) -> Option<bool> {
+        None
+    }
 }
 
+/// Compares whole files by digest, using Blake3 or, in FIPS mode, sha256.
 #[derive(Clone, Copy)]
-pub struct Blake3Comparer;
+pub struct DigestComparer;
 
-impl Comparer for Blake3Comparer {
-    type Output = blake3::Hash;
+impl Comparer for DigestComparer {
+    type Output = String;
 
     fn build(path: &Path) -> io::Result<Self::Output> {
-        let mut hasher = blake3::Hasher::new();
-        let mut reader = std::fs::File::open(path)?;
-        io::copy(&mut reader, &mut hasher)?;
-        Ok(hasher.finalize())
+        crate::policy::comparison_algorithm().hash(path)
     }
 }
 
@@ -38,69 +42,194 @@ impl Comparer for ImprintComparer {
     fn build(path: &Path) -> io::Result<Self::Output> {
         Imprint::new(path)
     }
+
+    /// Fresh digests of both files, taken with the same algorithm, settle the question better
+    /// than imprints would, and at no cost.
+    fn settle(left: &Path, right: &Path) -> Option<bool> {
+        let cache = crate::cache::get()?;
+        Algorithm::ALL.into_iter().find_map(|algorithm| {
+            let left = cache.lookup(algorithm, left)?;
+            let right = cache.lookup(algorithm, right)?;
+            Some(left.eq_ignore_ascii_case(&right))
+        })
+    }
 }
 
 pub fn compare_contents<C>(
     left: impl IntoIterator<Item = (PathBuf, PathBuf)>,
     right: &HashMap<PathBuf, PathBuf>,
+    links: bool,
     verbose: bool,
 ) -> crate::Result<bool>
 where
     C: Comparer<Output: Send> + Copy,
 {
     let colorize = io::stdout().is_terminal();
-    
+
     let message = "match".green();
     let mut has_failure = false;
 
     for (relative, absolute) in left {
         if let Some(right_hand_absolute_path) = right.get(&relative) {
-            if !compare_with::<C>(&absolute, right_hand_absolute_path, colorize)? {
-                has_failure = true;
-            } else if verbose {
-                let path = relative.display();
-                println!("{message} {path}");
+            let is_match =
+                if links && (absolute.is_symlink() || right_hand_absolute_path.is_symlink()) {
+                    Some(compare_link_targets(
+                        &absolute,
+                        right_hand_absolute_path,
+                        colorize,
+                    ))
+                } else {
+                    compare_with::<C>(&absolute, right_hand_absolute_path, colorize)?
+                };
+
+            match is_match {
+                Some(true) if verbose => {
+                    let _hidden = crate::progress::hide();
+                    let path = DisplayPath(&relative);
+                    println!("{message} {path}");
+                }
+                Some(false) => has_failure = true,
+                _ => {}
             }
         } else {
             print_missing(relative, colorize);
             has_failure = true;
         }
+
+        crate::progress::file_done();
     }
-    
+
     Ok(has_failure)
 }
 
 fn print_missing(relative: PathBuf, colorize: bool) {
+    let _hidden = crate::progress::hide();
     if colorize {
         let missing = "missing".yellow();
-        let relative = relative.display();
+        let relative = DisplayPath(&relative);
         println!("{missing} {relative}");
     } else {
-        let relative = relative.display();
+        let relative = DisplayPath(&relative);
         println!("missing {relative}");
     }
 }
 
-pub fn compare_with<T>(left: &Path, right: &Path, colorize: bool) -> crate::Result<bool>
+pub fn print_unlisted(relative: PathBuf, colorize: bool) {
+    let _hidden = crate::progress::hide();
+    if colorize {
+        let unlisted = "unlisted".yellow();
+        let relative = DisplayPath(&relative);
+        println!("{unlisted} {relative}");
+    } else {
+        let relative = DisplayPath(&relative);
+        println!("unlisted {relative}");
+    }
+}
+
+/// Links are equal only if both are links and both point at the same target, as written.
+fn compare_link_targets(left: &Path, right: &Path, colorize: bool) -> bool {
+    let is_match = match (fs::read_link(left), fs::read_link(right)) {
+        (Ok(left), Ok(right)) => left == right,
+        _ => false,
+    };
+
+    if !is_match {
+        print_mismatch(left, colorize);
+    }
+
+    is_match
+}
+
+fn print_mismatch(path: &Path, colorize: bool) {
+    let _hidden = crate::progress::hide();
+    if colorize {
+        let mismatch = "MISMATCH".red();
+        let path = DisplayPath(path);
+        println!("{mismatch} {path}");
+    } else {
+        println!("MISMATCH {}", DisplayPath(path));
+    }
+}
+
+/// Compare the contents of two files, returning `None` if either could not be read.
+pub fn compare_with<T>(left: &Path, right: &Path, colorize: bool) -> crate::Result<Option<bool>>
 where
     T: Comparer<Output: Send> + Copy,
 {
-    let tasks = &[left, right];
-    let tasks: io::Result<Vec<_>> = tasks
-        .into_par_iter()
-        .map(move |&path| T::build(path))
-        .collect();
+    if let Some(uniform) = T::settle(left, right) {
+        if !uniform {
+            print_mismatch(left, colorize);
+        }
+        return Ok(Some(uniform));
+    }
 
-    let uniform = tasks?.uniform();
-    if !uniform {
-        if colorize {
-            let mismatch = "MISMATCH".red();
-            let path = left.display();
-            println!("{mismatch} {path}");
-        } else {
-            println!("MISMATCH {}", left.display());
+    let paths = &[left, right];
+
+    // Two files on the same hard disk are read one after the other.
+    let tasks: Vec<_> = if crate::disk::same_spindle(left, right) {
+        paths.iter().map(|&path| T::build(path)).collect()
+    } else {
+        paths.into_par_iter().map(|&path| T::build(path)).collect()
+    };
+
+    // A file we can't read is reported as such rather than as a mismatch.
+    let mut outputs = Vec::with_capacity(tasks.len());
+    for (&path, task) in paths.iter().zip(tasks) {
+        match crate::error::recover(path, task)? {
+            Some(output) => outputs.push(output),
+            None => return Ok(None),
         }
     }
 
-    Ok(uniform)
+    let uniform = outputs.uniform();
+    if !uniform {
+        print_mismatch(left, colorize);
+    }
+
+    Ok(Some(uniform))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use hashbrown::HashMap;
+
+    use super::{compare_contents, DigestComparer};
+    use crate::temp::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn links_match_only_by_target_as_written() {
+        use std::os::unix::fs::symlink;
+
+        let temp = TempDir::new("targets").unwrap();
+        let left = temp.path().join("left");
+        let right = temp.path().join("right");
+        for dir in [&left, &right] {
+            fs::create_dir(dir).unwrap();
+            fs::write(dir.join("file"), "same").unwrap();
+        }
+
+        symlink("file", left.join("same")).unwrap();
+        symlink("file", right.join("same")).unwrap();
+        // Both lead to the same file, but aren't written the same way.
+        symlink("file", left.join("written")).unwrap();
+        symlink("./file", right.join("written")).unwrap();
+        // A link is never the same as a file, whatever it points at.
+        symlink("file", left.join("kind")).unwrap();
+        fs::write(right.join("kind"), "same").unwrap();
+
+        let has_failure = |name: &str| {
+            let relative = PathBuf::from(name);
+            let others = HashMap::from([(relative.clone(), right.join(name))]);
+            let pairs = [(relative, left.join(name))];
+            compare_contents::<DigestComparer>(pairs, &others, true, false).unwrap()
+        };
+
+        assert!(!has_failure("file"));
+        assert!(!has_failure("same"));
+        assert!(has_failure("written"));
+        assert!(has_failure("kind"));
+    }
 }
@@ -0,0 +1,268 @@
+use std::{
+    fmt,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+};
+
+use hashbrown::HashMap;
+use owo_colors::{OwoColorize, Style};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    fmt::DisplayPath,
+    hk::{self, Hashes},
+};
+
+/// The differences between two sum files, computed without reference to the files they describe.
+#[derive(Debug, Default, Serialize)]
+pub struct ManifestDiff {
+    algorithm: String,
+    added: Vec<Entry>,
+    removed: Vec<Entry>,
+    changed: Vec<Change>,
+    renamed: Vec<Rename>,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    name: String,
+    hash: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    name: String,
+    old: String,
+    new: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Rename {
+    old: String,
+    new: String,
+    hash: String,
+}
+
+impl ManifestDiff {
+    pub fn new(old: &Hashes, new: &Hashes) -> crate::Result<Self> {
+        if old.algorithm() != new.algorithm() {
+            return Err(Error::AlgorithmMismatch(old.algorithm(), new.algorithm()));
+        }
+
+        let old_entries = index(old)?;
+        let new_entries = index(new)?;
+
+        let mut diff = ManifestDiff {
+            algorithm: old.algorithm().name().into(),
+            ..Default::default()
+        };
+
+        // Entries present only in the new manifest are candidates for renames. We index them by
+        // digest so that each removed entry can claim a matching addition.
+        let mut additions: HashMap<String, Vec<&Path>> = HashMap::new();
+        for (name, &hash) in &new_entries {
+            if !old_entries.contains_key(name) {
+                additions
+                    .entry(hash.to_ascii_lowercase())
+                    .or_default()
+                    .push(name);
+            }
+        }
+
+        for candidates in additions.values_mut() {
+            // Sorted in reverse so that pop() hands out names in order.
+            candidates.sort_unstable_by(|a, b| b.cmp(a));
+        }
+
+        // Removed entries claim renames in order of name, so that when several share a digest,
+        // the same one is reported as renamed every time.
+        let mut old_names: Vec<_> = old_entries.keys().map(PathBuf::as_path).collect();
+        old_names.sort_unstable();
+
+        for name in old_names {
+            let hash = old_entries[name];
+            match new_entries.get(name) {
+                Some(&current) if current.eq_ignore_ascii_case(hash) => {}
+                Some(&current) => diff.changed.push(Change {
+                    name: display(name),
+                    old: hash.into(),
+                    new: current.into(),
+                }),
+                None => match additions
+                    .get_mut(&hash.to_ascii_lowercase())
+                    .and_then(|candidates| candidates.pop())
+                {
+                    Some(renamed) => diff.renamed.push(Rename {
+                        old: display(name),
+                        new: display(renamed),
+                        hash: hash.into(),
+                    }),
+                    None => diff.removed.push(Entry {
+                        name: display(name),
+                        hash: hash.into(),
+                    }),
+                },
+            }
+        }
+
+        for name in additions.into_values().flatten() {
+            diff.added.push(Entry {
+                name: display(name),
+                hash: new_entries[name].into(),
+            });
+        }
+
+        diff.added.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        diff.removed.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        diff.changed.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        diff.renamed.sort_unstable_by(|a, b| a.old.cmp(&b.old));
+
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.renamed.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("diff is always serializable")
+    }
+
+    pub fn print(&self) {
+        let colorize = io::stdout().is_terminal();
+
+        for entry in &self.added {
+            print_label("added", Style::new().green(), colorize, &entry.name);
+        }
+
+        for entry in &self.removed {
+            print_label("removed", Style::new().red(), colorize, &entry.name);
+        }
+
+        for change in &self.changed {
+            print_label("changed", Style::new().yellow(), colorize, &change.name);
+        }
+
+        for rename in &self.renamed {
+            let item = format_args!("{} -> {}", rename.old, rename.new);
+            print_label("renamed", Style::new().yellow(), colorize, item);
+        }
+    }
+}
+
+/// Map each name in `hashes` to its digest, refusing a sum file which lists any name twice, since
+/// we couldn't tell which of its digests to compare. Names are normalised, so that `./a.txt` and
+/// `a.txt` are the same entry.
+fn index(hashes: &Hashes) -> crate::Result<HashMap<PathBuf, &str>> {
+    let mut entries = HashMap::new();
+    for (name, hash) in hashes.entries() {
+        if entries.insert(hk::normalize_name(name), hash).is_some() {
+            return Err(Error::DuplicateEntry {
+                path: hashes.source().into(),
+                name: name.into(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Names are reported as text, with any bytes which aren't valid UTF-8 escaped.
+fn display(name: &Path) -> String {
+    DisplayPath(name).to_string()
+}
+
+fn print_label(label: &str, style: Style, colorize: bool, item: impl fmt::Display) {
+    if colorize {
+        let label = label.style(style);
+        println!("{label} {item}");
+    } else {
+        println!("{label} {item}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ManifestDiff;
+    use crate::{error::Error, hk::Hashes};
+
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn compare(old: &str, new: &str) -> crate::Result<ManifestDiff> {
+        let old = Hashes::parse("old/SHA256SUMS", old.as_bytes())?;
+        let new = Hashes::parse("new/SHA256SUMS", new.as_bytes())?;
+        ManifestDiff::new(&old, &new)
+    }
+
+    fn names<T>(entries: &[T], f: impl Fn(&T) -> String) -> Vec<String> {
+        entries.iter().map(f).collect()
+    }
+
+    #[test]
+    fn additions_removals_changes_and_renames_are_found() {
+        let old = "aaaa  same.txt\nbbbb  changed.txt\ncccc  gone.txt\ndddd  before.txt\n";
+        let new = "aaaa  same.txt\nBBBC  changed.txt\neeee  new.txt\nDDDD  after.txt\n";
+        let diff = compare(old, new).unwrap();
+
+        assert_eq!("sha256", diff.algorithm);
+        assert_eq!(vec!["new.txt"], names(&diff.added, |e| e.name.clone()));
+        assert_eq!(vec!["gone.txt"], names(&diff.removed, |e| e.name.clone()));
+        assert_eq!(
+            vec!["changed.txt"],
+            names(&diff.changed, |c| c.name.clone())
+        );
+        assert_eq!(
+            vec!["before.txt -> after.txt"],
+            names(&diff.renamed, |r| format!("{} -> {}", r.old, r.new))
+        );
+        assert!(!diff.is_empty());
+        assert!(compare(old, old).unwrap().is_empty());
+    }
+
+    #[test]
+    fn names_match_however_they_are_written() {
+        let old = format!("{EMPTY}  ./a.txt\n{EMPTY}  ./sub/./b.txt\n");
+        let new = format!("{EMPTY}  a.txt\n{EMPTY}  sub/b.txt\n");
+        assert!(compare(&old, &new).unwrap().is_empty());
+
+        let twice = format!("{EMPTY}  ./a.txt\n{EMPTY}  a.txt\n");
+        assert!(matches!(
+            compare(&twice, &new),
+            Err(Error::DuplicateEntry { .. })
+        ));
+    }
+
+    #[test]
+    fn renames_sharing_a_digest_pair_up_in_order() {
+        let old = format!("{EMPTY}  b.txt\n{EMPTY}  a.txt\n{EMPTY}  c.txt\n");
+        let new = format!("{EMPTY}  y.txt\n{EMPTY}  x.txt\n");
+
+        // However the maps happen to be ordered, the same pairs and leftover come out every time.
+        for _ in 0..20 {
+            let diff = compare(&old, &new).unwrap();
+            assert_eq!(
+                vec!["a.txt -> x.txt", "b.txt -> y.txt"],
+                names(&diff.renamed, |r| format!("{} -> {}", r.old, r.new))
+            );
+            assert_eq!(vec!["c.txt"], names(&diff.removed, |e| e.name.clone()));
+            assert!(diff.added.is_empty());
+        }
+    }
+
+    #[test]
+    fn duplicate_names_are_refused() {
+        let result = compare("aaaa  a.txt\nbbbb  a.txt\n", "aaaa  a.txt\n");
+        match result {
+            Err(Error::DuplicateEntry { path, name }) => {
+                assert_eq!("old/SHA256SUMS", path.to_str().unwrap());
+                assert_eq!("a.txt", name.to_str().unwrap());
+            }
+            _ => panic!("duplicate name accepted"),
+        }
+
+        assert!(compare("aaaa  a.txt\n", "aaaa  a.txt\naaaa  a.txt\n").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! Finding the hard disk behind each file.
+//!
+//! Reading several files at once from a hard disk sends its heads back and forth between them,
+//! which is far slower than reading the files one after another. Files on the same hard disk are
+//! therefore read one at a time, in the order in which they lie on the disk, while different disks
+//! are read side by side. Solid-state devices, and anything we can't identify, are read as
+//! concurrently as ever.
+
+use std::{
+    fs::{self, Metadata},
+    path::Path,
+    sync::OnceLock,
+};
+
+/// A hard disk, identified by the device number of the whole disk rather than of a partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Spindle(u64);
+
+/// Indices into a list of files, grouped for reading.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Files which may be read concurrently, in their original order.
+    pub solid: Vec<usize>,
+    /// One group per hard disk, each in the order in which its files lie on the disk.
+    pub spindles: Vec<Vec<usize>>,
+}
+
+/// Group `paths` by the hard disk on which each is stored.
+pub fn plan<P: AsRef<Path>>(paths: &[P]) -> Plan {
+    let mut plan = Plan::default();
+    let mut groups: Vec<(Spindle, Vec<usize>)> = Vec::new();
+
+    for (index, path) in paths.iter().enumerate() {
+        let spindle = fs::metadata(path).ok().and_then(|meta| spindle(&meta));
+        match spindle {
+            None => plan.solid.push(index),
+            Some(spindle) => match groups.iter_mut().find(|(other, _)| *other == spindle) {
+                Some((_, group)) => group.push(index),
+                None => groups.push((spindle, vec![index])),
+            },
+        }
+    }
+
+    for (_, mut group) in groups {
+        // Files whose place on the disk we can't learn are read last, in their original order.
+        group.sort_by_cached_key(|&index| {
+            sys::physical_offset(paths[index].as_ref()).unwrap_or(u64::MAX)
+        });
+        plan.spindles.push(group);
+    }
+
+    plan
+}
+
+/// The hard disk holding the file described by `meta`, or `None` if it's held on a solid-state
+/// device, or on something we can't identify.
+pub fn spindle(meta: &Metadata) -> Option<Spindle> {
+    sys::spindle(meta)
+}
+
+/// Whether any hard disk is attached to the machine, so that reads might need ordering.
+pub fn any_spindles() -> bool {
+    static ANY: OnceLock<bool> = OnceLock::new();
+    *ANY.get_or_init(sys::any_spindles)
+}
+
+/// Whether `left` and `right` are both stored on the same hard disk, and so shouldn't be read at
+/// the same time.
+pub fn same_spindle(left: &Path, right: &Path) -> bool {
+    let spindle = |path| fs::metadata(path).ok().and_then(|meta| spindle(&meta));
+    match (spindle(left), spindle(right)) {
+        (Some(left), Some(right)) => left == right,
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        collections::BTreeMap,
+        fs::{self, File, Metadata},
+        os::{fd::AsRawFd, unix::fs::MetadataExt},
+        path::Path,
+        sync::Mutex,
+    };
+
+    use super::Spindle;
+
+    /// Device numbers we've already looked up, since each lookup reads several files in sysfs.
+    static SPINDLES: Mutex<BTreeMap<u64, Option<Spindle>>> = Mutex::new(BTreeMap::new());
+
+    pub fn spindle(meta: &Metadata) -> Option<Spindle> {
+        let dev = meta.dev();
+        let mut spindles = SPINDLES.lock().unwrap();
+        *spindles.entry(dev).or_insert_with(|| lookup(dev))
+    }
+
+    pub fn any_spindles() -> bool {
+        let Ok(disks) = fs::read_dir("/sys/block") else {
+            return false;
+        };
+
+        disks.flatten().any(|disk| {
+            fs::read_to_string(disk.path().join("queue/rotational"))
+                .is_ok_and(|rotational| rotational.trim() == "1")
+        })
+    }
+
+    /// Find the disk behind a device number in sysfs, and whether it's rotational.
+    ///
+    /// Filesystems such as btrfs and overlayfs report device numbers of their own, which sysfs
+    /// doesn't know, so files stored on them are treated as being on solid-state devices.
+    fn lookup(dev: u64) -> Option<Spindle> {
+        let (major, minor) = (libc::major(dev), libc::minor(dev));
+        let device = fs::canonicalize(format!("/sys/dev/block/{major}:{minor}")).ok()?;
+
+        // A partition's queue belongs to the disk which holds it.
+        let disk = if device.join("partition").exists() {
+            device.parent()?
+        } else {
+            &device
+        };
+
+        let rotational = fs::read_to_string(disk.join("queue/rotational")).ok()?;
+        if rotational.trim() != "1" {
+            return None;
+        }
+
+        let dev = fs::read_to_string(disk.join("dev")).ok()?;
+        parse_dev(&dev).map(Spindle)
+    }
+
+    /// Parse a device number as sysfs writes it, e.g. `8:0`.
+    pub(super) fn parse_dev(s: &str) -> Option<u64> {
+        let (major, minor) = s.trim().split_once(':')?;
+        Some(libc::makedev(major.parse().ok()?, minor.parse().ok()?))
+    }
+
+    // The layouts of struct fiemap and struct fiemap_extent, most of which only the kernel reads.
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+    }
+
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    /// A request for the first extent of a file.
+    #[repr(C)]
+    struct FirstExtent {
+        header: Fiemap,
+        extent: FiemapExtent,
+    }
+
+    const FS_IOC_FIEMAP: u32 = libc::_IOWR::<Fiemap>('f' as u32, 11);
+
+    /// The position on disk of the start of the file at `path`, or `None` if the filesystem
+    /// won't say, or the file has no data.
+    pub fn physical_offset(path: &Path) -> Option<u64> {
+        let file = File::open(path).ok()?;
+
+        // SAFETY: the request is plain data, for which all zeroes is a valid value.
+        let mut request: FirstExtent = unsafe { std::mem::zeroed() };
+        request.header.length = u64::MAX;
+        request.header.extent_count = 1;
+
+        // SAFETY: the request has room for the single extent it asks for, and the descriptor is
+        // owned by `file`.
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut request) };
+
+        (result == 0 && request.header.mapped_extents == 1).then_some(request.extent.physical)
+    }
+}
+
+// Elsewhere we can't tell a hard disk from any other device, and everything is read as before.
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{fs::Metadata, path::Path};
+
+    use super::Spindle;
+
+    pub fn any_spindles() -> bool {
+        false
+    }
+
+    pub fn spindle(_meta: &Metadata) -> Option<Spindle> {
+        None
+    }
+
+    pub fn physical_offset(_path: &Path) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::plan;
+    use crate::temp::TempDir;
+
+    #[test]
+    fn every_file_is_planned_once() {
+        let temp = TempDir::new("plan").unwrap();
+        let dir = temp.path();
+
+        let mut paths = Vec::new();
+        for n in 0..20 {
+            let path = dir.join(n.to_string());
+            fs::write(&path, vec![n as u8; 5000]).unwrap();
+            paths.push(path);
+        }
+        paths.push(dir.join("missing"));
+
+        let plan = plan(&paths);
+
+        let mut planned: Vec<_> = plan
+            .solid
+            .iter()
+            .chain(plan.spindles.iter().flatten())
+            .collect();
+        planned.sort();
+        assert!(planned.into_iter().copied().eq(0..paths.len()));
+
+        // A file we can't find is left for the ordinary path to report.
+        assert!(plan.solid.contains(&20));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn device_numbers_parse() {
+        use super::sys::parse_dev;
+
+        assert_eq!(Some(libc::makedev(8, 0)), parse_dev("8:0\n"));
+        assert_eq!(Some(libc::makedev(254, 16)), parse_dev("254:16"));
+        assert_eq!(None, parse_dev("8"));
+        assert_eq!(None, parse_dev("a:b"));
+    }
+}
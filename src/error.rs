@@ -1,4 +1,66 @@
-use std::{fmt::Display, io, rc::Rc};
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{alg::Algorithm, fmt::DisplayPath};
+
+/// Exit status when a file does not match its expected digest, or two files or trees differ.
+pub const EXIT_MISMATCH: i32 = 1;
+
+/// Exit status when a file named by a sum file does not exist and nothing failed to match.
+pub const EXIT_MISSING: i32 = 2;
+
+/// Exit status when any file or directory could not be read.
+pub const EXIT_IO_ERROR: i32 = 3;
+
+/// Exit status when the command line is invalid.
+pub const EXIT_USAGE: i32 = 4;
+
+/// Exit status when a sum file or other input is malformed.
+pub const EXIT_MALFORMED: i32 = 5;
+
+/// Set when any file or directory could not be read.
+static IO_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Abort multi-file operations on the first I/O error rather than carrying on.
+static FAIL_FAST: AtomicBool = AtomicBool::new(false);
+
+pub fn set_fail_fast(fail_fast: bool) {
+    FAIL_FAST.store(fail_fast, Ordering::Relaxed);
+}
+
+/// True if any file or directory could not be read.
+pub fn io_failed() -> bool {
+    IO_FAILED.load(Ordering::Relaxed)
+}
+
+/// Report an I/O error without interrupting the current operation.
+pub fn report_io_error(message: impl Display) {
+    IO_FAILED.store(true, Ordering::Relaxed);
+    let _hidden = crate::progress::hide_from_stderr();
+    eprintln!("error: {message}");
+}
+
+/// Handle the result of an operation on a single file within a multi-file operation.
+///
+/// Errors are reported and swallowed so that the remaining files can be processed, unless we are
+/// failing fast, in which case the error is returned.
+pub fn recover<T>(path: &Path, result: io::Result<T>) -> crate::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if FAIL_FAST.load(Ordering::Relaxed) => Err(Error::io(Operation::Read, path, e)),
+        Err(e) => {
+            report_io_error(format_args!("{}: {e}", path.display()));
+            Ok(None)
+        }
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub enum OperationKind {
@@ -6,14 +68,84 @@ pub enum OperationKind {
     Dir,
 }
 
+/// What we were doing to a file when an I/O error occurred.
+#[derive(Copy, Clone, Debug)]
+pub enum Operation {
+    Open,
+    Read,
+    Write,
+    Walk,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Open => f.write_str("cannot open"),
+            Operation::Read => f.write_str("cannot read"),
+            Operation::Write => f.write_str("cannot write"),
+            Operation::Walk => f.write_str("cannot walk"),
+        }
+    }
+}
+
+/// A line in a sum file which cannot be accepted.
+#[derive(Clone, Debug)]
+pub struct BadLine {
+    /// One-based line number.
+    pub number: usize,
+    pub text: String,
+    pub kind: BadLineKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BadLineKind {
+    /// The line is not an entry at all.
+    Format,
+    /// The entry names a file outside the sum file's directory.
+    Outside,
+}
+
+impl Display for BadLineKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BadLineKind::Format => f.write_str("bad hash file format"),
+            BadLineKind::Outside => f.write_str(
+                "entry lies outside the sum file's directory (pass --allow-outside to permit)",
+            ),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Error {
     // We only store the first object; the second object is assumed to be
     // A) wrong and B) the opposite kind.
     InvalidOperation(OperationKind),
-    Io(Rc<io::Error>),
+    Io {
+        operation: Option<Operation>,
+        path: Option<PathBuf>,
+        source: Arc<io::Error>,
+    },
     UnknownAlgorithm(String),
-    HashFile,
+    AlgorithmMismatch(Algorithm, Algorithm),
+    HashFile {
+        path: PathBuf,
+        line: Option<BadLine>,
+    },
+    /// A sum file lists the same name more than once.
+    DuplicateEntry {
+        path: PathBuf,
+        name: PathBuf,
+    },
+    CacheDir,
+    Pattern(String),
+    /// The algorithm offers less collision resistance than the minimum strength.
+    WeakAlgorithm {
+        algorithm: Algorithm,
+        min_strength: u32,
+    },
+    /// The algorithm isn't approved for use in FIPS mode.
+    NotApproved(Algorithm),
 }
 
 impl Display for Error {
@@ -23,18 +155,112 @@ impl Display for Error {
                 OperationKind::Child => f.write_str("attempt to compare dir against parent dir"),
                 OperationKind::Dir => f.write_str("cannot compare directory against non-directory"),
             },
-            Error::Io(e) => e.fmt(f),
+            Error::Io {
+                operation,
+                path,
+                source,
+            } => match (operation, path) {
+                (Some(operation), Some(path)) => {
+                    write!(f, "{operation} {}: {source}", path.display())
+                }
+                (None, Some(path)) => write!(f, "{}: {source}", path.display()),
+                _ => source.fmt(f),
+            },
             Error::UnknownAlgorithm(algorithm) => write!(f, "unknown algorithm: {algorithm}"),
-            Error::HashFile => f.write_str("bad hash file format"),
+            Error::AlgorithmMismatch(left, right) => write!(
+                f,
+                "sum files use different algorithms ({left} and {right}) and cannot be compared"
+            ),
+            Error::HashFile { path, line } => match line {
+                Some(line) => write!(
+                    f,
+                    "cannot parse {}, line {}: {}: {:?}",
+                    path.display(),
+                    line.number,
+                    line.kind,
+                    line.text
+                ),
+                None => write!(f, "cannot parse {}: bad hash file format", path.display()),
+            },
+            Error::DuplicateEntry { path, name } => write!(
+                f,
+                "cannot compare {}: {} is listed more than once",
+                path.display(),
+                DisplayPath(name)
+            ),
+            Error::Pattern(e) => write!(f, "bad pattern: {e}"),
+            Error::CacheDir => f.write_str("unable to locate cache directory; pass a cache file"),
+            Error::WeakAlgorithm {
+                algorithm,
+                min_strength,
+            } => write!(
+                f,
+                "{} offers {} bits of collision resistance, below the minimum strength of {min_strength}",
+                algorithm.name(),
+                algorithm.strength()
+            ),
+            Error::NotApproved(algorithm) => write!(
+                f,
+                "{} is not approved in FIPS mode; use sha256 or sha512",
+                algorithm.name()
+            ),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl Error {
+    /// An I/O error which occurred while performing `operation` on `path`.
+    pub fn io(operation: Operation, path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Error::Io {
+            operation: Some(operation),
+            path: Some(path.into()),
+            source: Arc::new(source),
+        }
+    }
+
+    /// The status with which the process should exit on encountering this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } => EXIT_IO_ERROR,
+            Error::HashFile { .. } | Error::DuplicateEntry { .. } => EXIT_MALFORMED,
+            Error::InvalidOperation(_)
+            | Error::UnknownAlgorithm(_)
+            | Error::AlgorithmMismatch(..)
+            | Error::CacheDir
+            | Error::Pattern(_)
+            | Error::WeakAlgorithm { .. }
+            | Error::NotApproved(_) => EXIT_USAGE,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for Error {
     fn from(v: io::Error) -> Self {
-        Self::Io(Rc::new(v))
+        Error::Io {
+            operation: None,
+            path: None,
+            source: Arc::new(v),
+        }
+    }
+}
+
+/// Attach the path and operation to an I/O error.
+pub trait Context<T> {
+    fn context(self, operation: Operation, path: impl AsRef<Path>) -> crate::Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, operation: Operation, path: impl AsRef<Path>) -> crate::Result<T> {
+        self.map_err(|e| Error::io(operation, path.as_ref(), e))
     }
 }
 
@@ -43,3 +269,45 @@ impl From<crate::alg::UnknownAlgorithmError> for Error {
         Error::UnknownAlgorithm(e.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::Path};
+
+    use super::{BadLine, BadLineKind, Context, Error, Operation, EXIT_IO_ERROR, EXIT_MALFORMED};
+
+    #[test]
+    fn errors_cross_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Error>();
+    }
+
+    #[test]
+    fn errors_name_the_file() {
+        let result: io::Result<()> = Err(io::ErrorKind::NotFound.into());
+        let e = result
+            .context(Operation::Open, Path::new("SHA256SUMS"))
+            .unwrap_err();
+
+        assert!(e.to_string().starts_with("cannot open SHA256SUMS: "));
+        assert_eq!(EXIT_IO_ERROR, e.exit_code());
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let e = Error::HashFile {
+            path: "SHA256SUMS".into(),
+            line: Some(BadLine {
+                number: 3,
+                text: "junk".into(),
+                kind: BadLineKind::Format,
+            }),
+        };
+
+        assert_eq!(
+            "cannot parse SHA256SUMS, line 3: bad hash file format: \"junk\"",
+            e.to_string()
+        );
+        assert_eq!(EXIT_MALFORMED, e.exit_code());
+    }
+}
@@ -1,4 +1,7 @@
-use std::fmt::{self, LowerHex};
+use std::{
+    fmt::{self, LowerHex},
+    path::Path,
+};
 
 pub struct LowerHexFormatter(pub Vec<u8>);
 
@@ -10,3 +13,21 @@ impl LowerHex for LowerHexFormatter {
         Ok(())
     }
 }
+
+/// Displays a path as written where it is valid UTF-8, escaping every other byte as `\xNN`.
+///
+/// Unlike `Path::display`, which replaces such bytes with U+FFFD, this keeps distinct names
+/// distinct.
+pub struct DisplayPath<'a>(pub &'a Path);
+
+impl fmt::Display for DisplayPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.0.as_os_str().as_encoded_bytes().utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            for u in chunk.invalid() {
+                write!(f, "\\x{u:02x}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,458 @@
 use std::{
+    fmt,
     fs::File,
-    io::{self, Write},
+    io::{self, Read},
     path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
 };
 
 use digest::{Digest, Output};
+use rayon::prelude::*;
 
-pub fn hash_to_digest<T: Digest + Write>(
-    path: impl AsRef<Path>,
-    mut digest: T,
-) -> io::Result<Output<T>> {
-    let mut reader = File::open(path)?;
-    io::copy(&mut reader, &mut digest)?;
+use crate::{
+    alg::Algorithm,
+    disk::{self, Plan},
+};
+
+/// Files at least this large are hashed with BLAKE3 by mapping them into memory and hashing the
+/// mapping on every core. Smaller files aren't worth the cost of mapping and splitting the work.
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Mapped files are hashed a window at a time, so that we notice promptly if the file shrinks.
+const MMAP_WINDOW: usize = 64 * 1024 * 1024;
+
+/// Read buffers are a whole number of pages, so that every read starts on a page boundary.
+const PAGE_SIZE: usize = 4096;
+
+const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The largest buffer we'll read into; two of these are held for every file being read.
+const MAX_BUFFER_SIZE: usize = 1 << 30;
+
+/// The least buffer used to read a file smaller than a single buffer, in case it has grown.
+const MIN_SMALL_BUFFER: usize = 8 * 1024;
+
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_SIZE);
+
+/// Drop file contents from the page cache once we've read them.
+static NO_CACHE_POLLUTION: AtomicBool = AtomicBool::new(false);
+
+pub fn set_no_cache_pollution(no_cache_pollution: bool) {
+    NO_CACHE_POLLUTION.store(no_cache_pollution, Ordering::Relaxed);
+}
+
+fn no_cache_pollution() -> bool {
+    NO_CACHE_POLLUTION.load(Ordering::Relaxed)
+}
+
+/// Set the size of each of the two buffers used when reading files.
+pub fn set_buffer_size(size: ByteSize) {
+    BUFFER_SIZE.store(round_buffer_size(size), Ordering::Relaxed);
+}
+
+/// `size` rounded up to a whole number of pages, and held to `MAX_BUFFER_SIZE`.
+fn round_buffer_size(size: ByteSize) -> usize {
+    size.0
+        .div_ceil(PAGE_SIZE)
+        .max(1)
+        .checked_mul(PAGE_SIZE)
+        .map_or(MAX_BUFFER_SIZE, |size| size.min(MAX_BUFFER_SIZE))
+}
+
+fn buffer_size() -> usize {
+    BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// The number of files worth hashing together with `hash_all`.
+///
+/// Batching lets io_uring keep many files in flight, and lets files on a hard disk be read in the
+/// order in which they lie. Otherwise it would only cost us the planning, and hold back results,
+/// so files are hashed one at a time as they come.
+pub fn batch_size() -> usize {
+    if cfg!(all(feature = "io_uring", target_os = "linux")) || disk::any_spindles() {
+        256
+    } else {
+        1
+    }
+}
+
+/// Hash each of `paths` with `algorithm`.
+///
+/// Files on the same hard disk are read one at a time, in the order in which they lie on the
+/// disk, while each hard disk is read on a thread of its own. The rest are read together through
+/// io_uring, where it's available, or else one at a time.
+pub fn hash_all<P: AsRef<Path> + Sync>(
+    algorithm: Algorithm,
+    paths: &[P],
+) -> Vec<io::Result<String>> {
+    // A single file has nothing to be ordered against.
+    if paths.len() < 2 {
+        return hash_solid(algorithm, paths);
+    }
+
+    let Plan { solid, spindles } = disk::plan(paths);
+    if spindles.is_empty() {
+        return hash_solid(algorithm, paths);
+    }
+
+    let solid_paths: Vec<&Path> = solid.iter().map(|&index| paths[index].as_ref()).collect();
+    let (solid_results, spindle_results) = rayon::join(
+        || hash_solid(algorithm, &solid_paths),
+        || {
+            spindles
+                .par_iter()
+                .flat_map_iter(|group| {
+                    group
+                        .iter()
+                        .map(|&index| (index, algorithm.hash(&paths[index])))
+                })
+                .collect::<Vec<_>>()
+        },
+    );
+
+    reassemble(
+        paths.len(),
+        solid.into_iter().zip(solid_results).chain(spindle_results),
+    )
+}
+
+/// Put the results for each of `len` files, given with their indices, back in order.
+fn reassemble(
+    len: usize,
+    indexed: impl IntoIterator<Item = (usize, io::Result<String>)>,
+) -> Vec<io::Result<String>> {
+    let mut results: Vec<_> = (0..len).map(|_| None).collect();
+    for (index, result) in indexed {
+        results[index] = Some(result);
+    }
+
+    results
+        .into_iter()
+        .map(|result| result.expect("every file is hashed"))
+        .collect()
+}
+
+/// Hash files which may be read concurrently.
+///
+/// Where io_uring is available, small files are read together through io_uring. This bypasses
+/// the digest cache and the page cache advice, so it is used only when neither is wanted. Large
+/// files gain nothing from sharing the ring, and BLAKE3 would lose its mapping and hashing on
+/// every core, so they're hashed one at a time as usual.
+fn hash_solid<P: AsRef<Path>>(algorithm: Algorithm, paths: &[P]) -> Vec<io::Result<String>> {
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    if crate::cache::get().is_none() && !no_cache_pollution() {
+        // Files we can't stat are left for the ring to report.
+        let (small, large): (Vec<usize>, Vec<usize>) = (0..paths.len()).partition(|&index| {
+            std::fs::metadata(&paths[index]).map_or(true, |meta| meta.len() < MMAP_THRESHOLD)
+        });
+
+        let small_paths: Vec<&Path> = small.iter().map(|&index| paths[index].as_ref()).collect();
+        if let Some(small_results) = crate::uring::hash_all(&small_paths, algorithm) {
+            // Files the ring didn't get to, should it have failed, are hashed the usual way.
+            let small_results = small_results
+                .into_iter()
+                .zip(&small_paths)
+                .map(|(result, path)| result.unwrap_or_else(|| algorithm.hash(path)));
+            let large_results = large
+                .into_iter()
+                .map(|index| (index, algorithm.hash(&paths[index])));
+            return reassemble(
+                paths.len(),
+                small.into_iter().zip(small_results).chain(large_results),
+            );
+        }
+    }
+
+    paths.iter().map(|path| algorithm.hash(path)).collect()
+}
+
+pub fn hash_to_digest<T: Digest>(path: impl AsRef<Path>, mut digest: T) -> io::Result<Output<T>> {
+    let file = File::open(path)?;
+    read_chunks(file, |chunk| digest.update(chunk))?;
     Ok(digest.finalize())
 }
 
-pub fn hash_to_string<T: Digest + Write>(path: impl AsRef<Path>, digest: T) -> io::Result<String> {
+pub fn hash_to_string<T: Digest>(path: impl AsRef<Path>, digest: T) -> io::Result<String> {
     hash_to_digest(path, digest).map(|result| fmt_hex(result.as_slice()))
 }
 
-fn fmt_hex(bytes: &[u8]) -> String {
+/// Read `file` to the end, passing each chunk to `f`.
+///
+/// Files larger than a single buffer are read on a second thread into a pair of buffers, so that
+/// the next chunk is being read while the last is being hashed.
+pub fn read_chunks(file: File, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let size = buffer_size();
+
+    // Pipes, devices and the like report a length of zero, or none at all, whatever they hold.
+    let len = file
+        .metadata()
+        .ok()
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len());
+    let mut reader = Reader::new(file);
+
+    if let Some(len) = len.filter(|&len| len < size as u64) {
+        // One byte more than the file's length lets us see the end of it in a single read, should
+        // it not have grown since.
+        let mut buf = vec![0; (len as usize + 1).max(MIN_SMALL_BUFFER)];
+        loop {
+            let n = reader.fill(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            f(&buf[..n]);
+        }
+    }
+
+    let (full_tx, full_rx) = mpsc::sync_channel::<io::Result<(Vec<u8>, usize)>>(1);
+    let (free_tx, free_rx) = mpsc::sync_channel::<Vec<u8>>(2);
+    free_tx.send(vec![0; size]).unwrap();
+    free_tx.send(vec![0; size]).unwrap();
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            // The receiving end hangs up when it's finished, or on hitting an error of its own.
+            while let Ok(mut buf) = free_rx.recv() {
+                let result = reader.fill(&mut buf).map(|n| (buf, n));
+                let done = !matches!(result, Ok((_, n)) if n > 0);
+                if full_tx.send(result).is_err() || done {
+                    return;
+                }
+            }
+        });
+
+        loop {
+            let (buf, n) = full_rx.recv().expect("reader always sends a final chunk")?;
+            if n == 0 {
+                return Ok(());
+            }
+            f(&buf[..n]);
+            let _ = free_tx.send(buf);
+        }
+    })
+}
+
+/// A file being read from start to finish.
+struct Reader {
+    file: File,
+    offset: u64,
+    no_cache_pollution: bool,
+}
+
+impl Reader {
+    fn new(file: File) -> Self {
+        let no_cache_pollution = no_cache_pollution();
+
+        // Advice is only ever a hint, so there's nothing to be done if it's refused.
+        let _ = sys::advise_sequential(&file);
+
+        Self {
+            file,
+            offset: 0,
+            no_cache_pollution,
+        }
+    }
+
+    /// Read into `buf` until it is full or the file ends, returning the number of bytes read.
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        crate::throttle::consume(filled);
+        crate::progress::add_bytes(filled as u64);
+
+        // Once the data is in our buffer, the kernel's copy is of no further use to us.
+        if self.no_cache_pollution && filled > 0 {
+            let _ = sys::advise_dont_need(&self.file, self.offset, filled as u64);
+        }
+
+        self.offset += filled as u64;
+        Ok(filled)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+mod sys {
+    use std::{fs::File, io, os::fd::AsRawFd};
+
+    pub fn advise_sequential(file: &File) -> io::Result<()> {
+        advise(file, 0, 0, libc::POSIX_FADV_SEQUENTIAL)
+    }
+
+    pub fn advise_dont_need(file: &File, offset: u64, len: u64) -> io::Result<()> {
+        advise(file, offset, len, libc::POSIX_FADV_DONTNEED)
+    }
+
+    fn advise(file: &File, offset: u64, len: u64, advice: libc::c_int) -> io::Result<()> {
+        let offset = offset.try_into().map_err(|_| io::ErrorKind::InvalidInput)?;
+        let len = len.try_into().map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        // SAFETY: posix_fadvise only reads its arguments, and the descriptor is owned by `file`.
+        match unsafe { libc::posix_fadvise(file.as_raw_fd(), offset, len, advice) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+// Elsewhere we have no way to advise the kernel, and we read as we otherwise would.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+mod sys {
+    use std::{fs::File, io};
+
+    pub fn advise_sequential(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn advise_dont_need(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A number of bytes, written as e.g. `65536`, `256K`, `4M` or `1G`.
+#[derive(Clone, Copy, Debug)]
+pub struct ByteSize(usize);
+
+/// The largest size accepted; nothing checksum is given a size for is sensibly larger.
+const MAX_BYTE_SIZE: u64 = 16 << 30;
+
+impl ByteSize {
+    pub const fn new(bytes: usize) -> Self {
+        Self(bytes)
+    }
+
+    pub fn bytes(self) -> usize {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = ByteSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|u: char| !u.is_ascii_digit()).unwrap_or(s.len());
+        let (digits, unit) = s.split_at(split);
+
+        let scale: usize = match unit.to_ascii_uppercase().trim_end_matches("IB") {
+            "" | "B" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            _ => return Err(ByteSizeError(s.into())),
+        };
+
+        match digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(scale))
+        {
+            Some(size) if size > 0 && size as u64 <= MAX_BYTE_SIZE => Ok(ByteSize(size)),
+            _ => Err(ByteSizeError(s.into())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ByteSizeError(String);
+
+impl fmt::Display for ByteSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bad size (expected e.g. 256K, 4M, at most 16G): {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ByteSizeError {}
+
+pub fn hash_blake3(path: impl AsRef<Path>) -> io::Result<String> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let meta = file.metadata()?;
+
+    // A mapping is read as it's hashed, out of sight of the rate limit.
+    if meta.is_file() && meta.len() >= MMAP_THRESHOLD && !crate::throttle::is_limited() {
+        // Hashing parts of the mapping on several cores would have a hard disk seeking between
+        // them.
+        let parallel = disk::spindle(&meta).is_none();
+        if let Some(hash) = hash_mapped(&file, meta.len(), parallel)? {
+            return Ok(hash);
+        }
+    }
+
+    hash_to_string(path, blake3::Hasher::new())
+}
+
+/// Hash a mapping of `file` in parallel, returning `None` if the file changed length while we
+/// were hashing it, in which case it should be hashed again by reading it.
+pub fn hash_mapped(file: &File, len: u64, parallel: bool) -> io::Result<Option<String>> {
+    // SAFETY: the mapping is read-only, but another process could still truncate the file while
+    // it's mapped, and touching a page beyond the new end of the file would raise SIGBUS. We
+    // check the length of the file before hashing each window, which catches a truncation between
+    // windows but not one in the middle of a window; that still kills the process, as the README
+    // warns.
+    let map = unsafe { memmap2::Mmap::map(file)? };
+
+    #[cfg(unix)]
+    map.advise(memmap2::Advice::Sequential)?;
+
+    let no_cache_pollution = no_cache_pollution();
+    let mut hasher = blake3::Hasher::new();
+    let mut offset = 0;
+
+    for window in map.chunks(MMAP_WINDOW) {
+        if file.metadata()?.len() < len {
+            return Ok(None);
+        }
+        if parallel {
+            hasher.update_rayon(window);
+        } else {
+            hasher.update(window);
+        }
+        crate::progress::add_bytes(window.len() as u64);
+
+        // Pages still mapped into our address space can't be dropped from the page cache, so
+        // we unmap each window before advising the kernel.
+        #[cfg(unix)]
+        if no_cache_pollution {
+            // SAFETY: the mapping is read-only and backed by the file, so pages we've given up
+            // are simply read again should we touch them, and we won't.
+            unsafe {
+                let _ = map.unchecked_advise_range(
+                    memmap2::UncheckedAdvice::DontNeed,
+                    offset,
+                    window.len(),
+                );
+            }
+            let _ = sys::advise_dont_need(file, offset as u64, window.len() as u64);
+        }
+        offset += window.len();
+    }
+
+    // If the file grew instead, we've hashed only part of it.
+    if file.metadata()?.len() != len {
+        return Ok(None);
+    }
+
+    Ok(Some(fmt_hex(hasher.finalize().as_slice())))
+}
+
+pub fn fmt_hex(bytes: &[u8]) -> String {
     use std::fmt::Write;
     let mut buf = String::with_capacity(bytes.len() * 2);
     for &u in bytes {
@@ -27,3 +460,100 @@ fn fmt_hex(bytes: &[u8]) -> String {
     }
     buf
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::{
+        hash_mapped, hash_to_string, read_chunks, round_buffer_size, ByteSize, MAX_BUFFER_SIZE,
+        PAGE_SIZE,
+    };
+    use crate::temp::TempDir;
+
+    #[test]
+    fn mapped_and_streamed_digests_agree() {
+        let temp = TempDir::new("mmap").unwrap();
+        let path = temp.path().join("file");
+        let content: Vec<u8> = (0..300_000u32).map(|u| (u % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let mapped = hash_mapped(&file, content.len() as u64, true).unwrap();
+        let serial = hash_mapped(&file, content.len() as u64, false).unwrap();
+        let streamed = hash_to_string(&path, blake3::Hasher::new()).unwrap();
+
+        assert_eq!(Some(&streamed), mapped.as_ref());
+        assert_eq!(Some(streamed), serial);
+    }
+
+    #[test]
+    fn chunks_cover_the_whole_file() {
+        let temp = TempDir::new("chunks").unwrap();
+        let path = temp.path().join("file");
+        let content: Vec<u8> = (0..5_000_000u32).map(|u| (u % 253) as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let mut read = Vec::new();
+        read_chunks(File::open(&path).unwrap(), |chunk| {
+            read.extend_from_slice(chunk)
+        })
+        .unwrap();
+
+        assert!(read == content);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipes_are_read_a_buffer_at_a_time() {
+        use std::{io::Write, os::fd::FromRawFd, thread};
+
+        let mut fds = [0; 2];
+        // SAFETY: pipe writes two new descriptors into the array, which we then own.
+        assert_eq!(0, unsafe { libc::pipe(fds.as_mut_ptr()) });
+        let (read, mut write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let content: Vec<u8> = (0..200_000u32).map(|u| (u % 241) as u8).collect();
+        let writer = thread::spawn({
+            let content = content.clone();
+            move || write.write_all(&content)
+        });
+
+        // A pipe reports a length of zero, which mustn't be taken for the size of its contents.
+        let mut chunks = 0;
+        let mut received = Vec::new();
+        read_chunks(read, |chunk| {
+            chunks += 1;
+            received.extend_from_slice(chunk)
+        })
+        .unwrap();
+        writer.join().unwrap().unwrap();
+
+        assert!(received == content);
+        assert!(chunks < 10, "{chunks} chunks");
+    }
+
+    #[test]
+    fn sizes_parse() {
+        let size = |s: &str| s.parse::<ByteSize>().map(|size| size.0).ok();
+
+        assert_eq!(Some(65536), size("65536"));
+        assert_eq!(Some(256 * 1024), size("256K"));
+        assert_eq!(Some(4 << 20), size("4MiB"));
+        assert_eq!(Some(1 << 30), size("1g"));
+        assert_eq!(None, size("0"));
+        assert_eq!(None, size("4X"));
+        assert_eq!(None, size("M"));
+        assert_eq!(Some(16 << 30), size("16G"));
+        assert_eq!(None, size("1024G"));
+        assert_eq!(None, size("18446744073709551615"));
+    }
+
+    #[test]
+    fn buffer_sizes_are_whole_pages_within_reason() {
+        assert_eq!(PAGE_SIZE, round_buffer_size(ByteSize(1)));
+        assert_eq!(2 * PAGE_SIZE, round_buffer_size(ByteSize(PAGE_SIZE + 1)));
+        assert_eq!(MAX_BUFFER_SIZE, round_buffer_size(ByteSize(16 << 30)));
+        assert_eq!(MAX_BUFFER_SIZE, round_buffer_size(ByteSize(usize::MAX)));
+    }
+}
@@ -1,52 +1,226 @@
-use core::{fmt, slice};
+use core::{fmt, slice, str};
 use std::{
     borrow::Cow,
+    collections::VecDeque,
     fs, io,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use owo_colors::OwoColorize;
-use regex::Regex;
+use regex::bytes::Regex;
 use uncased::AsUncased;
 
-use crate::{alg::Algorithm, error::Error};
+use crate::{
+    alg::Algorithm,
+    error::{BadLine, BadLineKind, Context, Error, Operation},
+    fmt::DisplayPath,
+};
 
 pub struct Hashes {
+    /// The sum file these entries were read from.
+    source: PathBuf,
+    /// The directory which entries must not lead out of, even through symbolic links, unless
+    /// they may lie anywhere.
+    root: Option<PathBuf>,
     algorithm: Algorithm,
     files: Vec<ValidateTask>,
 }
 
+/// How to treat questionable lines when reading a sum file.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadPolicy {
+    /// Warn about lines which can't be accepted, rather than rejecting the whole file.
+    pub lenient: bool,
+    /// Accept absolute names, and names which climb out of the sum file's directory.
+    pub allow_outside: bool,
+}
+
 impl Hashes {
-    pub fn from_path(path: impl AsRef<Path>) -> crate::Result<Self> {
+    /// Read the sum file at `path`.
+    ///
+    /// Comments and blank lines are skipped. Any other line which isn't a valid entry, or which
+    /// names a file outside the sum file's directory, causes the whole file to be rejected,
+    /// unless we're being lenient, in which case such lines are reported as warnings and
+    /// otherwise ignored.
+    pub fn from_path(path: impl AsRef<Path>, policy: ReadPolicy) -> crate::Result<Self> {
         let path = path.as_ref();
         let algorithm = read_alg_from_path(path)?.parse()?;
-        let text = fs::read_to_string(path)?;
-        let entries = text.lines().filter(|&s| !s.starts_with('#'));
-        let parser = EntryParser::default();
+        let text = fs::read(path).context(Operation::Read, path)?;
+        Self::from_bytes(path, algorithm, &text, policy)
+    }
 
-        let mut files = Vec::new();
+    /// Parse `text` as though it had been read from the sum file at `path`.
+    fn from_bytes(
+        path: &Path,
+        algorithm: Algorithm,
+        text: &[u8],
+        policy: ReadPolicy,
+    ) -> crate::Result<Self> {
+        let dir = path.parent().expect("path must refer to file");
 
-        // This should work with or without asterisks.
-        // ref: https://www.howtogeek.com/67241/htg-explains-what-are-md5-sha-1-hashes-and-how-do-i-check-them/
-        for entry in entries {
-            let (hash, name) = parser.parse(entry)?;
+        let (entries, bad_lines) = parse_entries(text, policy.allow_outside);
+
+        if let Some(line) = bad_lines.first().filter(|_| !policy.lenient) {
+            return Err(Error::HashFile {
+                path: path.into(),
+                line: Some(line.clone()),
+            });
+        }
+
+        for line in &bad_lines {
+            eprintln!(
+                "warning: {}, line {}: {}: {:?}",
+                path.display(),
+                line.number,
+                line.kind,
+                line.text
+            );
+        }
+
+        let improperly_formatted = bad_lines
+            .iter()
+            .filter(|line| line.kind == BadLineKind::Format)
+            .count();
 
-            // We have to assume the relative path here is correct -- hence the unwrap.
-            let path = path.parent().expect("path must refer to file").join(name);
-            files.push(ValidateTask::new(path, name, hash));
+        match improperly_formatted {
+            0 => {}
+            1 => eprintln!("warning: 1 line is improperly formatted"),
+            n => eprintln!("warning: {n} lines are improperly formatted"),
         }
 
-        Ok(Self { algorithm, files })
+        // A file with nothing but bad lines is not a sum file at all.
+        if entries.is_empty() && !bad_lines.is_empty() {
+            return Err(Error::HashFile {
+                path: path.into(),
+                line: None,
+            });
+        }
+
+        let files = entries
+            .into_iter()
+            .map(|(hash, name)| ValidateTask::new(dir.join(&name), name, hash))
+            .collect();
+
+        // A sum file named without a directory lives in the current one.
+        let root = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+
+        Ok(Self {
+            source: path.into(),
+            root: (!policy.allow_outside).then(|| root.into()),
+            algorithm,
+            files,
+        })
+    }
+
+    /// Parse a sum file held in memory, for testing.
+    #[cfg(test)]
+    pub fn parse(path: impl AsRef<Path>, text: &[u8]) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let algorithm = read_alg_from_path(path)?.parse()?;
+        Self::from_bytes(path, algorithm, text, ReadPolicy::default())
+    }
+
+    /// Resolve each entry relative to `root` rather than the directory containing the sum file.
+    pub fn rebase(mut self, root: impl AsRef<Path>) -> Self {
+        let root = root.as_ref();
+        for file in &mut self.files {
+            file.path = root.join(&file.name);
+        }
+        if self.root.is_some() {
+            self.root = Some(root.into());
+        }
+        self
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The name and expected hash of each file listed in the sum file.
+    pub fn entries(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.files
+            .iter()
+            .map(|file| (file.name.as_path(), file.hash.as_str()))
+    }
+
+    /// The paths at which the files listed in the sum file are expected to be found.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+
+    /// The relative names of the files listed in the sum file.
+    pub fn names(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.name.as_path())
+    }
+
+    /// Reorder entries, and therefore the order in which they are verified.
+    pub fn sort_by_cached_key<K: Ord>(&mut self, mut f: impl FnMut(&Path) -> K) {
+        self.files.sort_by_cached_key(|file| f(&file.name));
     }
 
     /// If you don't use this iterator, nothing actually gets verified.
     #[must_use]
     pub fn verify(&'_ self) -> Validator<'_> {
+        // Where the root itself can't be resolved, neither can anything in it, and every entry
+        // will be reported missing.
+        let root = self
+            .root
+            .as_ref()
+            .map(|root| root.canonicalize().unwrap_or_else(|_| root.clone()));
+
         Validator {
+            root,
             algorithm: self.algorithm,
             source: self.files.iter(),
+            batch_size: 1,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// Split a sum file into entries (hash, name) and improperly formatted lines.
+///
+/// Sum files are read as bytes, since on Unix a file name may be any sequence of bytes.
+fn parse_entries(text: &[u8], allow_outside: bool) -> (Vec<(&str, PathBuf)>, Vec<BadLine>) {
+    let parser = EntryParser::default();
+    let mut entries = Vec::new();
+    let mut bad_lines = Vec::new();
+
+    for (idx, line) in text.split(|&u| u == b'\n').enumerate() {
+        // Lines may end in CRLF, as written on Windows. A name which really does end in a
+        // carriage return will have been escaped.
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b"#") || line.trim_ascii().is_empty() {
+            continue;
         }
+
+        // This should work with or without asterisks.
+        // ref: https://www.howtogeek.com/67241/htg-explains-what-are-md5-sha-1-hashes-and-how-do-i-check-them/
+        let kind = match parser.parse(line) {
+            Some((_, name)) if !allow_outside && !is_contained(&name) => BadLineKind::Outside,
+            Some(entry) => {
+                entries.push(entry);
+                continue;
+            }
+            None => BadLineKind::Format,
+        };
+
+        bad_lines.push(BadLine {
+            number: idx + 1,
+            text: String::from_utf8_lossy(line).into_owned(),
+            kind,
+        });
     }
+
+    (entries, bad_lines)
 }
 
 #[derive(Debug)]
@@ -55,36 +229,167 @@ struct EntryParser {
 }
 
 impl EntryParser {
-    fn parse<'a>(&self, entry: &'a str) -> crate::Result<(&'a str, &'a str)> {
-        let cx = self.rx.captures(entry).ok_or(Error::HashFile)?;
-        let hash = cx.get(1).ok_or(Error::HashFile)?.as_str();
-        let name = cx.get(2).ok_or(Error::HashFile)?.as_str();
-        Ok((hash, name))
+    fn parse<'a>(&self, entry: &'a [u8]) -> Option<(&'a str, PathBuf)> {
+        // Coreutils marks entries whose names contain escapes with a leading backslash.
+        let (escaped, entry) = match entry.strip_prefix(b"\\") {
+            Some(entry) => (true, entry),
+            None => (false, entry),
+        };
+
+        let cx = self.rx.captures(entry)?;
+        let hash = str::from_utf8(cx.get(1)?.as_bytes()).ok()?;
+        let name = cx.get(2)?.as_bytes();
+
+        // A name with a backslash in it would have been escaped by coreutils, so one which
+        // wasn't must have come from Windows, where the backslash is a separator.
+        let name = if escaped {
+            Cow::Owned(unescape_name(name)?)
+        } else if name.contains(&b'\\') {
+            Cow::Owned(
+                name.iter()
+                    .map(|&u| if u == b'\\' { b'/' } else { u })
+                    .collect(),
+            )
+        } else {
+            Cow::Borrowed(name)
+        };
+
+        Some((hash, path_from_bytes(&name)?))
     }
 }
 
 impl Default for EntryParser {
     fn default() -> Self {
         Self {
-            rx: Regex::new(r"^(\S+)\s+\*?(.+)$").unwrap(),
+            // Unicode is disabled so that names may contain any byte.
+            rx: Regex::new(r"(?-u)^(\S+)\s+\*?(.+)$").unwrap(),
+        }
+    }
+}
+
+/// Format an entry as coreutils would, so that names containing backslashes or line breaks
+/// survive the round trip. Names are otherwise written byte for byte.
+pub fn format_entry(hash: &str, name: &Path) -> Vec<u8> {
+    let name = escape_name(name.as_os_str().as_encoded_bytes());
+    let mut line = Vec::with_capacity(hash.len() + name.len() + 3);
+
+    if let Cow::Owned(_) = name {
+        line.push(b'\\');
+    }
+
+    line.extend_from_slice(hash.as_bytes());
+    line.extend_from_slice(b"  ");
+    line.extend_from_slice(&name);
+    line
+}
+
+/// Escape backslashes and line breaks in `name`, borrowing it if there are none.
+pub fn escape_name(name: &[u8]) -> Cow<'_, [u8]> {
+    if !name.iter().any(|u| matches!(u, b'\\' | b'\n' | b'\r')) {
+        return Cow::Borrowed(name);
+    }
+
+    let mut escaped = Vec::with_capacity(name.len() + 2);
+    for &u in name {
+        match u {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            u => escaped.push(u),
+        }
+    }
+
+    Cow::Owned(escaped)
+}
+
+/// Reverse `escape_name`, returning `None` for an unknown or incomplete escape.
+pub fn unescape_name(name: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+
+    while let Some(&u) = bytes.next() {
+        if u != b'\\' {
+            unescaped.push(u);
+            continue;
+        }
+
+        match bytes.next()? {
+            b'\\' => unescaped.push(b'\\'),
+            b'n' => unescaped.push(b'\n'),
+            b'r' => unescaped.push(b'\r'),
+            _ => return None,
+        }
+    }
+
+    Some(unescaped)
+}
+
+/// True if `name`, taken relative to the sum file's directory, stays within it.
+fn is_contained(name: &Path) -> bool {
+    // Drive letters mean nothing to Unix, but a name like `C:/Windows` is plainly absolute.
+    let bytes = name.as_os_str().as_encoded_bytes();
+    if let [drive, b':', ..] = bytes {
+        if drive.is_ascii_alphabetic() {
+            return false;
+        }
+    }
+
+    let mut depth = 0usize;
+    for component in name.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
         }
     }
+
+    true
+}
+
+/// On Unix, a path is any sequence of bytes. Elsewhere, we can only accept names which are valid
+/// UTF-8.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    Some(OsStr::from_bytes(bytes).into())
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    str::from_utf8(bytes).ok().map(PathBuf::from)
 }
 
-fn read_alg_from_path(path: &Path) -> crate::Result<Cow<str>> {
+/// Sum files are expected to be named either for their algorithm (e.g. `SHA256SUMS`) or with the
+/// algorithm as their extension (e.g. `release.sha256`).
+fn read_alg_from_path(path: &Path) -> crate::Result<Cow<'_, str>> {
     path.extension()
-        .ok_or(Error::HashFile)
+        .or_else(|| path.file_name())
+        .ok_or_else(|| Error::HashFile {
+            path: path.into(),
+            line: None,
+        })
         .map(|s| s.to_string_lossy())
 }
 
+/// `name` without any `.` components, so that `./a.txt` and `a.txt` compare equal.
+pub fn normalize_name(name: &Path) -> PathBuf {
+    name.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
 pub struct ValidateTask {
     path: PathBuf,
-    name: String,
+    name: PathBuf,
     hash: String,
 }
 
 impl ValidateTask {
-    fn new(path: impl Into<PathBuf>, name: impl Into<String>, hash: impl Into<String>) -> Self {
+    fn new(path: impl Into<PathBuf>, name: impl Into<PathBuf>, hash: impl Into<String>) -> Self {
         Self {
             path: path.into(),
             name: name.into(),
@@ -92,8 +397,8 @@ impl ValidateTask {
         }
     }
 
-    fn validate(&self, algorithm: Algorithm) -> io::Result<HashResult> {
-        let actual = match algorithm.hash(&self.path) {
+    fn validate(&self, actual: io::Result<String>) -> io::Result<HashResult> {
+        let actual = match actual {
             Ok(actual) => actual,
             Err(e) if e.kind() == io::ErrorKind::NotFound => {
                 return Ok(HashResult::Missing);
@@ -116,21 +421,81 @@ enum HashResult {
 }
 
 pub struct Validator<'a> {
+    /// The resolved directory which entries must not lead out of, if any.
+    root: Option<PathBuf>,
     algorithm: Algorithm,
     source: slice::Iter<'a, ValidateTask>,
+    batch_size: usize,
+    pending: VecDeque<(&'a ValidateTask, io::Result<String>)>,
+}
+
+impl Validator<'_> {
+    /// Hash files `n` at a time, ahead of their being validated.
+    pub fn prefetch(self, n: usize) -> Self {
+        Self {
+            batch_size: n.max(1),
+            ..self
+        }
+    }
+
+    fn fill(&mut self) {
+        let batch: Vec<_> = self.source.by_ref().take(self.batch_size).collect();
+        let admitted: Vec<_> = batch.iter().map(|file| self.admits(&file.path)).collect();
+        let paths: Vec<_> = batch
+            .iter()
+            .zip(&admitted)
+            .filter(|(_, &admitted)| admitted)
+            .map(|(file, _)| &file.path)
+            .collect();
+
+        let mut hashes = crate::hash::hash_all(self.algorithm, &paths).into_iter();
+        for (file, admitted) in batch.into_iter().zip(admitted) {
+            let actual = if admitted {
+                hashes.next().expect("every admitted file is hashed")
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "entry leads outside the sum file's directory through a symbolic link \
+                     (pass --allow-outside to permit)",
+                ))
+            };
+            self.pending.push_back((file, actual));
+        }
+    }
+
+    /// Whether `path` may be read: a name which stays within the directory may still lead out
+    /// of it through a symbolic link, so the check is made again once links are resolved.
+    fn admits(&self, path: &Path) -> bool {
+        let Some(root) = &self.root else {
+            return true;
+        };
+
+        match path.canonicalize() {
+            Ok(path) => path.starts_with(root),
+            // Whatever isn't there is reported as missing once we try to read it.
+            Err(_) => true,
+        }
+    }
 }
 
 impl<'a> Iterator for Validator<'a> {
-    type Item = io::Result<Validation<'a>>;
+    type Item = crate::Result<Validation<'a>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let file = self.source.next()?;
-        let result = match file.validate(self.algorithm) {
-            Ok(result) => result,
-            Err(e) => return Some(Err(e)),
-        };
+        // Files which can't be read are reported and skipped, unless we're failing fast.
+        loop {
+            if self.pending.is_empty() {
+                self.fill();
+            }
 
-        Some(Ok(Validation { file, result }))
+            let (file, actual) = self.pending.pop_front()?;
+            crate::progress::file_done();
+            match crate::error::recover(&file.path, file.validate(actual)) {
+                Ok(Some(result)) => return Some(Ok(Validation { file, result })),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
 }
 
@@ -139,21 +504,232 @@ pub struct Validation<'a> {
     result: HashResult,
 }
 
+impl<'a> Validation<'a> {
+    pub fn name(&self) -> &'a Path {
+        &self.file.name
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self.result, HashResult::Ok)
+    }
+
+    pub fn is_missing(&self) -> bool {
+        matches!(self.result, HashResult::Missing)
+    }
+}
+
 impl fmt::Display for Validation<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.result {
             HashResult::Ok => {
                 let ok = "OK".bright_green();
-                write!(f, "{ok} {}", self.file.name)
+                write!(f, "{ok} {}", DisplayPath(&self.file.name))
             }
             HashResult::Mismatch(_result) => {
                 let result = "FAIL".red();
-                write!(f, "{result} {}", self.file.name)
+                write!(f, "{result} {}", DisplayPath(&self.file.name))
             }
             HashResult::Missing => {
                 let missing = "MISSING".yellow();
-                write!(f, "{missing} {}", self.file.name)
+                write!(f, "{missing} {}", DisplayPath(&self.file.name))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{format_entry, is_contained, normalize_name, parse_entries, Hashes};
+    use crate::{alg::Algorithm, error::BadLineKind, temp::TempDir};
+
+    #[test]
+    fn sum_files_are_rebased_and_named_for_their_algorithm() {
+        let temp = TempDir::new("rebase").unwrap();
+        let dir = temp.path();
+
+        let text = "abc123  ./one.txt\ndef456  sub/two.txt\n";
+        for (name, algorithm) in [
+            ("SHA256SUMS", Algorithm::Sha256),
+            ("md5sum", Algorithm::Md5),
+            ("release.blake3", Algorithm::Blake3),
+        ] {
+            std::fs::write(dir.join(name), text).unwrap();
+            let hashes = Hashes::from_path(dir.join(name), Default::default()).unwrap();
+            assert_eq!(algorithm, hashes.algorithm(), "{name}");
+        }
+
+        std::fs::write(dir.join("release.txt"), text).unwrap();
+        assert!(Hashes::from_path(dir.join("release.txt"), Default::default()).is_err());
+
+        let hashes = Hashes::from_path(dir.join("SHA256SUMS"), Default::default()).unwrap();
+
+        // Entries are found beside the sum file until rebased onto another directory.
+        let paths: Vec<_> = hashes.paths().map(Path::to_path_buf).collect();
+        assert_eq!(vec![dir.join("./one.txt"), dir.join("sub/two.txt")], paths);
+
+        let hashes = hashes.rebase("/srv/release");
+        let paths: Vec<_> = hashes.paths().collect();
+        assert_eq!(
+            vec![
+                Path::new("/srv/release/one.txt"),
+                Path::new("/srv/release/sub/two.txt")
+            ],
+            paths
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_out_of_the_directory_are_not_followed() {
+        use std::{fs, os::unix::fs::symlink};
+
+        let temp = TempDir::new("contain").unwrap();
+        let base = temp.path();
+        let dir = base.join("release");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(base.join("secret"), "secret").unwrap();
+        fs::write(dir.join("inside"), "inside").unwrap();
+        symlink(base, dir.join("link")).unwrap();
+
+        let secret = Algorithm::Sha256.hash(base.join("secret")).unwrap();
+        let inside = Algorithm::Sha256.hash(dir.join("inside")).unwrap();
+        let text =
+            format!("{secret}  link/secret\n{inside}  inside\n{inside}  link/release/inside\n");
+        fs::write(dir.join("SHA256SUMS"), text).unwrap();
+
+        let hashes = Hashes::from_path(dir.join("SHA256SUMS"), Default::default()).unwrap();
+        let verified: Vec<_> = hashes
+            .verify()
+            .map(|validation| validation.unwrap().name().to_path_buf())
+            .collect();
+
+        // A link which leads back into the directory is as good as any other name for a file.
+        assert_eq!(
+            vec![
+                PathBuf::from("inside"),
+                PathBuf::from("link/release/inside")
+            ],
+            verified
+        );
+
+        let policy = super::ReadPolicy {
+            allow_outside: true,
+            ..Default::default()
+        };
+        let hashes = Hashes::from_path(dir.join("SHA256SUMS"), policy).unwrap();
+        assert_eq!(
+            3,
+            hashes
+                .verify()
+                .filter(|v| v.as_ref().unwrap().is_ok())
+                .count()
+        );
+    }
+
+    #[test]
+    fn names_are_normalised() {
+        assert_eq!(PathBuf::from("a.txt"), normalize_name(Path::new("./a.txt")));
+        assert_eq!(PathBuf::from("a/b"), normalize_name(Path::new("./a/./b")));
+        assert_eq!(PathBuf::from("../a"), normalize_name(Path::new("../a")));
+    }
+
+    #[test]
+    fn blank_lines_and_crlf_are_accepted() {
+        let text = b"# comment\r\nabc123  one.txt\r\n\r\n   \ndef456 *two.txt\r\n";
+        let (entries, bad_lines) = parse_entries(text, false);
+
+        assert_eq!(
+            vec![("abc123", "one.txt".into()), ("def456", "two.txt".into())],
+            entries
+        );
+        assert!(bad_lines.is_empty());
+    }
+
+    #[test]
+    fn bad_lines_are_numbered() {
+        let text = b"abc123  one.txt\njunk\n\ndef456  two.txt\nmore-junk\n";
+        let (entries, bad_lines) = parse_entries(text, false);
+
+        assert_eq!(2, entries.len());
+        let numbers: Vec<_> = bad_lines.iter().map(|line| line.number).collect();
+        assert_eq!(vec![2, 5], numbers);
+        assert_eq!("junk", bad_lines[0].text);
+    }
+
+    #[test]
+    fn escaped_names_round_trip() {
+        for name in [
+            "plain.txt",
+            "back\\slash",
+            "new\nline",
+            "carriage\rreturn",
+            "\\",
+        ] {
+            let line = format_entry("abc123", Path::new(name));
+            let (entries, bad_lines) = parse_entries(&line, false);
+
+            assert!(bad_lines.is_empty(), "{line:?}");
+            assert_eq!(vec![("abc123", PathBuf::from(name))], entries);
+        }
+    }
+
+    #[test]
+    fn coreutils_escapes_are_decoded() {
+        let (entries, _) = parse_entries(b"\\abc123  a\\\\b\\nc\n", false);
+        assert_eq!(vec![("abc123", PathBuf::from("a\\b\nc"))], entries);
+
+        let (_, bad_lines) = parse_entries(b"\\abc123  bad\\escape\n", false);
+        assert_eq!(1, bad_lines.len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_kept_byte_for_byte() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let line = b"abc123  caf\xe9.txt\n";
+        let (entries, bad_lines) = parse_entries(line, false);
+
+        assert!(bad_lines.is_empty());
+        assert_eq!(b"caf\xe9.txt", entries[0].1.as_os_str().as_bytes());
+        assert_eq!(
+            &line[..line.len() - 1],
+            &format_entry("abc123", &entries[0].1)[..]
+        );
+    }
+
+    #[test]
+    fn names_outside_the_directory_are_rejected() {
+        for name in [
+            "../secret",
+            "a/../../secret",
+            "/etc/shadow",
+            "C:/Windows/win.ini",
+        ] {
+            assert!(!is_contained(Path::new(name)), "{name}");
+        }
+
+        for name in ["a/b", "./a", "a/../b", "..a"] {
+            assert!(is_contained(Path::new(name)), "{name}");
+        }
+
+        let text = b"abc123  ../../home/user/.ssh/id_ed25519\n";
+        let (entries, bad_lines) = parse_entries(text, false);
+        assert!(entries.is_empty());
+        assert_eq!(BadLineKind::Outside, bad_lines[0].kind);
+
+        let (entries, _) = parse_entries(text, true);
+        assert_eq!(1, entries.len());
+    }
+
+    #[test]
+    fn windows_separators_are_normalised() {
+        let (entries, _) = parse_entries(b"abc123  dir\\file.txt\n", false);
+        assert_eq!(vec![("abc123", PathBuf::from("dir/file.txt"))], entries);
+
+        let (_, bad_lines) = parse_entries(b"abc123  ..\\..\\secret\n", false);
+        assert_eq!(BadLineKind::Outside, bad_lines[0].kind);
+    }
+}
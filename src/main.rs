@@ -1,44 +1,101 @@
 mod alg;
+mod bench;
+mod cache;
 mod cli;
 mod compare;
+mod diff;
+mod disk;
 mod error;
 mod fmt;
 mod hash;
 mod hk;
 mod iter;
+mod policy;
+mod progress;
+mod scrub;
+mod selftest;
+mod tag;
+mod temp;
+mod throttle;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring;
+mod walk;
 
 use std::{
-    io::{self, IsTerminal},
+    io::{self, IsTerminal, Write},
     path::{Path, PathBuf},
     process,
 };
 
-use cli::{Args, Command, FileCommand};
-use compare::{Blake3Comparer, ImprintComparer};
-use error::OperationKind;
-use hashbrown::HashMap;
-use hk::Hashes;
+use cli::{
+    Args, BenchCommand, Command, DiffCommand, FileCommand, ScrubCommand, XattrCommand, XattrPath,
+};
+use compare::{DigestComparer, ImprintComparer};
+use diff::ManifestDiff;
+use error::{Context, Operation, OperationKind, EXIT_IO_ERROR, EXIT_MISMATCH, EXIT_MISSING};
+use hashbrown::{HashMap, HashSet};
+use hk::{Hashes, ReadPolicy};
 use iter::IsUniform;
 use owo_colors::OwoColorize;
+use progress::ProgressFormat;
 use rayon::prelude::*;
 use uncased::AsUncased;
+use walk::{Filter, SymlinkPolicy, WalkOptions};
 
 use crate::error::Error;
 
 /// Environment key defining the default algorithm for this program.
 static CHECKSUM_DEFAULT_ALG: &str = "CHECKSUM_DEFAULT_ALG";
 
+/// Environment key defining the minimum strength of the algorithms this program may use.
+static CHECKSUM_MIN_STRENGTH: &str = "CHECKSUM_MIN_STRENGTH";
+
+/// Environment key restricting this program to FIPS-approved algorithms.
+static CHECKSUM_FIPS: &str = "CHECKSUM_FIPS";
+
 type Result<T, E = error::Error> = std::result::Result<T, E>;
 
 fn main() {
-    if let Err(e) = run(&Args::parse()) {
+    let result = run(&Args::parse());
+    progress::finish();
+
+    if let Err(e) = result {
         eprintln!("{e}");
-        process::exit(1);
+        process::exit(e.exit_code());
+    }
+
+    if error::io_failed() {
+        process::exit(EXIT_IO_ERROR);
+    }
+
+    if cache::bit_rot_detected() {
+        process::exit(EXIT_MISMATCH);
     }
 }
 
 fn run(args: &Args) -> Result<()> {
     args.validate()?;
+    error::set_fail_fast(args.fail_fast);
+    policy::set_fips(args.fips);
+    if let Some(bits) = args.min_strength {
+        policy::set_min_strength(bits, args.warn_weak);
+    }
+
+    if args.nice {
+        throttle::be_nice();
+    }
+    if let Some(rate) = args.rate_limit {
+        throttle::set_rate_limit(rate.bytes() as u64);
+    }
+
+    hash::set_no_cache_pollution(args.no_cache_pollution);
+    if let Some(size) = args.buffer_size {
+        hash::set_buffer_size(size);
+    }
+
+    if let Some(path) = args.cache_path()? {
+        cache::init(&path, args.cache_policy()).context(Operation::Open, &path)?;
+    }
 
     // First thing first, the primary arguments do not apply in the event we've received a
     // subcommand. In that case, we'll handle the subcommand and return.
@@ -56,7 +113,9 @@ fn run(args: &Args) -> Result<()> {
     if let Some(compare) = args.compare.as_deref() {
         let target: &Path = args.target().as_ref();
         if target.is_file() {
-            return compare_files(args.target(), compare);
+            return compare_files(args.target(), compare, args.progress);
+        } else if Path::new(compare).is_file() {
+            return compare_dir_with_sums(args.target(), compare, DirCompareContext(args));
         } else {
             return compare_dirs(args.target(), compare, DirCompareContext(args));
         }
@@ -67,7 +126,11 @@ fn run(args: &Args) -> Result<()> {
     // comparison mode, so we'll just hope he's done that.
 
     if let Some(hash) = args.assert.as_deref() {
-        let target = args.mode().hash(args.target())?;
+        policy::check(args.mode())?;
+        let target = args
+            .mode()
+            .hash(args.target())
+            .context(Operation::Read, args.target())?;
         return compare_hash_str(&target, hash);
     }
 
@@ -79,36 +142,139 @@ fn run(args: &Args) -> Result<()> {
 fn print_hash(args: &Args) -> Result<()> {
     let path = args.target();
     let mode = args.mode();
+    policy::check(mode)?;
 
-    let files: Vec<_> = read_files(path).collect();
-    for file in &files {
-        if files.len() == 1 {
-            println!("{}", mode.hash(file)?);
-        } else {
-            println!("{}  {}", mode.hash(file)?, file.display());
+    // Names are written byte for byte, so that the output is a sum file naming the same files.
+    let mut out = io::stdout().lock();
+
+    let files: Vec<_> = read_files(path, &args.walk_options().for_contents())?.collect();
+    if let Some(format) = args.progress {
+        progress::start(format, Some(mode), progress::scan(&files));
+    }
+
+    for batch in files.chunks(hash::batch_size()) {
+        for (file, hash) in batch.iter().zip(hash::hash_all(mode, batch)) {
+            progress::file_done();
+            let Some(hash) = error::recover(file, hash)? else {
+                continue;
+            };
+
+            let _hidden = progress::hide();
+            if files.len() == 1 {
+                writeln!(out, "{hash}")?;
+            } else {
+                out.write_all(&hk::format_entry(&hash, file))?;
+                out.write_all(b"\n")?;
+            }
         }
     }
 
     Ok(())
 }
 
-fn dispatch_command(_args: &Args, command: &Command) -> Result<()> {
+fn dispatch_command(args: &Args, command: &Command) -> Result<()> {
     match command {
-        Command::File(FileCommand { path }) => apply_checksums(path),
+        Command::File(FileCommand { path }) => {
+            apply_checksums(path, args.read_policy(), args.progress)
+        }
+        Command::Diff(DiffCommand { old, new, json }) => {
+            diff_checksums(old, new, *json, args.read_policy())
+        }
+        Command::Xattr(XattrCommand::Write(XattrPath { path })) => {
+            policy::check(args.mode())?;
+            let files = read_files(path, &args.walk_options().for_contents())?;
+            tag::write_all(files, args.mode(), args.verbose)
+        }
+        Command::Xattr(XattrCommand::Verify(XattrPath { path })) => {
+            policy::check(args.mode())?;
+            let files = read_files(path, &args.walk_options().for_contents())?;
+            if tag::verify_all(files, args.mode(), args.verbose)? {
+                process::exit(EXIT_MISMATCH);
+            }
+            Ok(())
+        }
+        Command::Scrub(scrub) => scrub_checksums(args, scrub),
+        Command::Bench(BenchCommand { path, size }) => {
+            bench::bench(path.as_deref().map(Path::new), *size)
+        }
+        Command::Selftest => {
+            if !selftest::selftest(args.verbose)? {
+                process::exit(EXIT_MISMATCH);
+            }
+            Ok(())
+        }
     }
 }
 
-fn apply_checksums(path: &str) -> Result<()> {
-    let hashes = Hashes::from_path(path)?;
+fn scrub_checksums(args: &Args, command: &ScrubCommand) -> Result<()> {
+    let path = Path::new(&command.path);
+    let summary = scrub::scrub(path, command.limit(), args.read_policy(), args.verbose)?;
+    println!("verified {} of {} files", summary.verified, summary.total);
+
+    exit_for_validation(summary.has_failure, summary.has_missing);
 
-    for exception in hashes.verify() {
+    Ok(())
+}
+
+fn diff_checksums(old: &str, new: &str, json: bool, policy: ReadPolicy) -> Result<()> {
+    let old = Hashes::from_path(old, policy)?;
+    let new = Hashes::from_path(new, policy)?;
+    let diff = ManifestDiff::new(&old, &new)?;
+
+    if json {
+        println!("{}", diff.to_json());
+    } else {
+        diff.print();
+    }
+
+    if !diff.is_empty() {
+        process::exit(EXIT_MISMATCH);
+    }
+
+    Ok(())
+}
+
+fn apply_checksums(path: &str, policy: ReadPolicy, progress: Option<ProgressFormat>) -> Result<()> {
+    let hashes = Hashes::from_path(path, policy)?;
+    policy::check(hashes.algorithm())?;
+    if let Some(format) = progress {
+        let algorithm = Some(hashes.algorithm());
+        progress::start(format, algorithm, progress::scan(hashes.paths()));
+    }
+
+    let mut has_failure = false;
+    let mut has_missing = false;
+
+    for exception in hashes.verify().prefetch(hash::batch_size()) {
         let exception = exception?;
+        if exception.is_missing() {
+            has_missing = true;
+        } else if !exception.is_ok() {
+            has_failure = true;
+        }
+
+        let _hidden = progress::hide();
         println!("{exception}");
     }
 
+    exit_for_validation(has_failure, has_missing);
     Ok(())
 }
 
+/// Exit with the status appropriate to the outcome of verifying a sum file, if anything failed.
+/// A mismatch takes precedence over a missing file.
+fn exit_for_validation(has_failure: bool, has_missing: bool) {
+    progress::finish();
+
+    if has_failure {
+        process::exit(EXIT_MISMATCH);
+    }
+
+    if has_missing {
+        process::exit(EXIT_MISSING);
+    }
+}
+
 fn compare_hash_str(left: &str, right: &str) -> Result<()> {
     let colorize = io::stdout().is_terminal();
     let right = right.as_uncased();
@@ -121,7 +287,7 @@ fn compare_hash_str(left: &str, right: &str) -> Result<()> {
         } else {
             let result = "False".red();
             println!("{result}");
-            process::exit(1);
+            process::exit(EXIT_MISMATCH);
         }
     } else {
         if left.as_uncased() == right {
@@ -129,25 +295,41 @@ fn compare_hash_str(left: &str, right: &str) -> Result<()> {
             Ok(())
         } else {
             println!("False");
-            process::exit(1);
+            process::exit(EXIT_MISMATCH);
         }
     }
 }
 
-fn compare_files(left: &str, right: &str) -> Result<()> {
+fn compare_files(left: &str, right: &str, progress: Option<ProgressFormat>) -> Result<()> {
     let tasks = &[left, right];
-    let tasks: io::Result<Vec<_>> = tasks
-        .into_par_iter()
-        .map(|&path| hash::hash_to_digest(path, blake3::Hasher::new()))
-        .collect();
+    let algorithm = policy::comparison_algorithm();
+    if let Some(format) = progress {
+        progress::start(format, Some(algorithm), progress::scan(tasks));
+    }
+
+    let hash = |&path: &&str| {
+        let hash = algorithm.hash(path).context(Operation::Read, path);
+        progress::file_done();
+        hash
+    };
+
+    // Two files on the same hard disk are read one after the other.
+    let tasks: Result<Vec<_>> = if disk::same_spindle(left.as_ref(), right.as_ref()) {
+        tasks.iter().map(hash).collect()
+    } else {
+        tasks.into_par_iter().map(hash).collect()
+    };
 
-    if tasks?.uniform() {
+    let tasks = tasks?;
+    progress::finish();
+
+    if tasks.uniform() {
         let result = "True".green();
         println!("{result}");
     } else {
         let result = "False".red();
         println!("{result}");
-        process::exit(1);
+        process::exit(EXIT_MISMATCH);
     }
 
     Ok(())
@@ -158,47 +340,172 @@ pub struct DirCompareContext<'a>(&'a Args);
 impl DirCompareContext<'_> {
     #[inline]
     fn full_comparison(&self) -> bool {
-        self.0.force_full_compare
+        // The quick comparison isn't a FIPS-approved method, and its reads, made out of our
+        // sight, can't be held to a rate limit.
+        self.0.force_full_compare || policy::is_fips() || throttle::is_limited()
     }
 
     #[inline]
     fn verbose(&self) -> bool {
         self.0.verbose
     }
+
+    #[inline]
+    fn read_policy(&self) -> ReadPolicy {
+        self.0.read_policy()
+    }
+
+    #[inline]
+    fn walk_options(&self) -> WalkOptions {
+        self.0.walk_options()
+    }
+
+    #[inline]
+    fn progress(&self) -> Option<ProgressFormat> {
+        self.0.progress
+    }
 }
 
 fn compare_dirs(left: &str, right: &str, context: DirCompareContext) -> Result<()> {
     ensure_distinct(left, right)?;
 
-    let left = read_files(left).filter_map(|path| {
-        get_relative_path(left.as_ref(), &path).map(|absolute| (absolute, path))
-    });
+    let options = context.walk_options();
 
-    let right: HashMap<_, _> = read_files(right)
+    let left: Vec<_> = read_files(left, &options)?
+        .filter_map(|path| get_relative_path(left.as_ref(), &path).map(|absolute| (absolute, path)))
+        .collect();
+
+    let right: HashMap<_, _> = read_files(right, &options)?
         .filter_map(|path| {
             get_relative_path(right.as_ref(), &path).map(|relative| (relative, path))
         })
         .collect();
 
+    // A quick comparison reads only part of each file, so only files can be counted.
+    if let Some(format) = context.progress() {
+        let algorithm = context.full_comparison().then(policy::comparison_algorithm);
+        let totals = if context.full_comparison() {
+            let paths = left.iter().map(|(_, path)| path);
+            let paths = paths.chain(right.values());
+            let totals = progress::scan(paths);
+            progress::Totals {
+                files: left.len() as u64,
+                ..totals
+            }
+        } else {
+            progress::Totals {
+                files: left.len() as u64,
+                bytes: None,
+            }
+        };
+        progress::start(format, algorithm, totals);
+    }
+
+    let links = options.symlinks == SymlinkPolicy::CompareTarget;
     let has_failure = if context.full_comparison() {
-        compare::compare_contents::<Blake3Comparer>(left, &right, context.verbose())?
+        compare::compare_contents::<DigestComparer>(left, &right, links, context.verbose())?
     } else {
-        compare::compare_contents::<ImprintComparer>(left, &right, context.verbose())?
+        compare::compare_contents::<ImprintComparer>(left, &right, links, context.verbose())?
     };
 
-    if !has_failure {
+    progress::finish();
+    if has_failure {
+        process::exit(EXIT_MISMATCH);
+    }
+
+    // If part of either tree could not be read, we can't claim that the trees are equal.
+    if !error::io_failed() {
         let message = "True".green();
         println!("{message}");
-    } else {
-        process::exit(1);
     }
 
     Ok(())
 }
 
+fn compare_dir_with_sums(dir: &str, sums: &str, context: DirCompareContext) -> Result<()> {
+    let colorize = io::stdout().is_terminal();
+    let manifest = Path::new(sums)
+        .canonicalize()
+        .context(Operation::Open, sums)?;
+    let hashes = Hashes::from_path(sums, context.read_policy())?.rebase(dir);
+    policy::check(hashes.algorithm())?;
+
+    if let Some(format) = context.progress() {
+        let algorithm = Some(hashes.algorithm());
+        progress::start(format, algorithm, progress::scan(hashes.paths()));
+    }
+
+    let mut has_failure = false;
+    let mut has_missing = false;
+
+    for validation in hashes.verify().prefetch(hash::batch_size()) {
+        let validation = validation?;
+        let _hidden = progress::hide();
+        if validation.is_missing() {
+            has_missing = true;
+            println!("{validation}");
+        } else if !validation.is_ok() {
+            has_failure = true;
+            println!("{validation}");
+        } else if context.verbose() {
+            println!("{validation}");
+        }
+    }
+
+    // Anything present in the tree but not named by the manifest is also a failure, with the
+    // exception of the manifest itself, which will often live in the directory it describes.
+    // Every listed entry is verified above; filters decide only which unlisted files count.
+
+    let visible: Vec<_> = read_files(dir, &context.walk_options().for_contents())?
+        .filter_map(|path| get_relative_path(dir.as_ref(), &path))
+        .collect();
+
+    for relative in find_unlisted(dir.as_ref(), &hashes, visible, &manifest) {
+        compare::print_unlisted(relative, colorize);
+        has_failure = true;
+    }
+
+    exit_for_validation(has_failure, has_missing);
+
+    // If part of either tree could not be read, we can't claim that the trees are equal.
+    if !error::io_failed() {
+        let message = "True".green();
+        println!("{message}");
+    }
+
+    Ok(())
+}
+
+/// The files in `visible`, relative to `dir`, which `hashes` doesn't list, other than the sum file
+/// at `manifest` itself and the state `checksum scrub` keeps beside it.
+fn find_unlisted(
+    dir: &Path,
+    hashes: &Hashes,
+    visible: Vec<PathBuf>,
+    manifest: &Path,
+) -> Vec<PathBuf> {
+    // Sum files made by e.g. `find . | xargs sha256sum` name their files `./a.txt`.
+    let listed: HashSet<_> = hashes.names().map(hk::normalize_name).collect();
+    let state = scrub::state_path(manifest);
+
+    visible
+        .into_iter()
+        .filter(|relative| !listed.contains(&hk::normalize_name(relative)))
+        .filter(|relative| {
+            !dir.join(relative)
+                .canonicalize()
+                .is_ok_and(|path| path == manifest || path == state)
+        })
+        .collect()
+}
+
 fn ensure_distinct(left: &str, right: &str) -> Result<()> {
-    let left = Path::new(left).canonicalize()?;
-    let right = Path::new(right).canonicalize()?;
+    let left = Path::new(left)
+        .canonicalize()
+        .context(Operation::Open, left)?;
+    let right = Path::new(right)
+        .canonicalize()
+        .context(Operation::Open, right)?;
 
     if left.ancestors().any(|ancestor| ancestor == right)
         || right.ancestors().any(|ancestor| ancestor == left)
@@ -209,24 +516,166 @@ fn ensure_distinct(left: &str, right: &str) -> Result<()> {
     Ok(())
 }
 
-fn read_files(path: &str) -> impl Iterator<Item = PathBuf> {
-    let files = walkdir::WalkDir::new(path).into_iter().filter_map(|entry| {
-        let entry = entry.ok()?;
-        let meta = entry.metadata().ok()?;
-
-        if meta.file_type().is_file() {
-            Some(entry.into_path())
-        } else {
-            None
-        }
-    });
-
-    files.filter(|path| {
-        path.file_name()
-            .map_or(false, |name| !name.to_string_lossy().starts_with('.'))
-    })
+fn read_files(path: &str, options: &WalkOptions) -> Result<impl Iterator<Item = PathBuf>> {
+    let mut filter = Filter::new(path.as_ref(), options)?;
+    let symlinks = options.symlinks;
+    let skip_errors = options.skip_errors;
+
+    let files = walkdir::WalkDir::new(path)
+        .follow_links(symlinks == SymlinkPolicy::Follow)
+        .into_iter()
+        .filter_entry(move |entry| {
+            if entry.path_is_symlink() && symlinks == SymlinkPolicy::Follow {
+                match filter.admits_link(entry.path()) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        eprintln!("warning: symlink escapes root: {}", entry.path().display());
+                        return false;
+                    }
+                    Err(e) => {
+                        walk::report_error(
+                            skip_errors,
+                            format_args!("{}: {e}", entry.path().display()),
+                        );
+                        return false;
+                    }
+                }
+            }
+
+            filter.accepts(entry.path(), entry.file_type().is_dir(), entry.depth())
+        })
+        .filter_map(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    if let Some(ancestor) = e.loop_ancestor() {
+                        let path = e.path().unwrap_or(ancestor);
+                        let message = format_args!(
+                            "symlink loop: {} -> {}",
+                            path.display(),
+                            ancestor.display()
+                        );
+                        walk::report_error(skip_errors, message);
+                    } else if let Some(path) = e.path().filter(|path| path.is_symlink()) {
+                        walk::report_error(
+                            skip_errors,
+                            format_args!("broken symlink: {}", path.display()),
+                        );
+                    } else {
+                        walk::report_error(skip_errors, e);
+                    }
+                    return None;
+                }
+            };
+
+            if entry.path_is_symlink() && symlinks == SymlinkPolicy::CompareTarget {
+                return Some(entry.into_path());
+            }
+
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(e) => {
+                    walk::report_error(skip_errors, e);
+                    return None;
+                }
+            };
+
+            if meta.file_type().is_file() {
+                Some(entry.into_path())
+            } else {
+                None
+            }
+        });
+
+    Ok(files)
 }
 
 fn get_relative_path(base: &Path, path: &Path) -> Option<PathBuf> {
     path.strip_prefix(base).map(|path| path.to_owned()).ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::{find_unlisted, get_relative_path, read_files};
+    use crate::{
+        hk::Hashes,
+        temp::TempDir,
+        walk::{SymlinkPolicy, WalkOptions},
+    };
+
+    #[cfg(unix)]
+    #[test]
+    fn followed_links_stay_in_the_tree_and_loops_end() {
+        use std::os::unix::fs::symlink;
+
+        let temp = TempDir::new("links").unwrap();
+        let base = temp.path();
+        let dir = base.join("tree");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+
+        symlink(dir.join("a.txt"), dir.join("sub/alias.txt")).unwrap();
+        symlink(base, dir.join("out")).unwrap();
+        symlink(&dir, dir.join("sub/loop")).unwrap();
+
+        let options = WalkOptions {
+            symlinks: SymlinkPolicy::Follow,
+            ..Default::default()
+        };
+        let mut files: Vec<_> = read_files(dir.to_str().unwrap(), &options)
+            .unwrap()
+            .filter_map(|path| get_relative_path(&dir, &path))
+            .collect();
+        files.sort();
+
+        // The link within the tree is followed; the one leading out of it, and the loop, aren't.
+        assert_eq!(
+            vec![PathBuf::from("a.txt"), PathBuf::from("sub/alias.txt")],
+            files
+        );
+        // The loop is reported as part of the tree which couldn't be walked.
+        assert!(crate::error::io_failed());
+    }
+
+    #[test]
+    fn audits_match_names_however_they_are_written() {
+        let temp = TempDir::new("audit").unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["a.txt", "sub/b.txt", "c.txt", "SHA256SUMS.scrub"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        // Listed as `find . | xargs sha256sum` would write them, with the sum file and its scrub
+        // state in the tree.
+        let manifest = dir.join("SHA256SUMS");
+        let text = format!(
+            "{}  ./a.txt\n{}  ./sub/b.txt\n",
+            crate::alg::Algorithm::Sha256
+                .hash(dir.join("a.txt"))
+                .unwrap(),
+            crate::alg::Algorithm::Sha256
+                .hash(dir.join("sub/b.txt"))
+                .unwrap(),
+        );
+        fs::write(&manifest, text).unwrap();
+
+        let hashes = Hashes::from_path(&manifest, Default::default())
+            .unwrap()
+            .rebase(dir);
+        let verified: Vec<_> = hashes.verify().map(Result::unwrap).collect();
+        assert!(verified.iter().all(|validation| validation.is_ok()));
+
+        let options = WalkOptions::default().for_contents();
+        let visible: Vec<_> = read_files(dir.to_str().unwrap(), &options)
+            .unwrap()
+            .filter_map(|path| get_relative_path(dir, &path))
+            .collect();
+        let unlisted = find_unlisted(dir, &hashes, visible, &manifest.canonicalize().unwrap());
+
+        assert_eq!(vec![PathBuf::from("c.txt")], unlisted);
+    }
+}
@@ -0,0 +1,129 @@
+//! Which algorithms may be used.
+//!
+//! Collisions can be made to order for MD5 and SHA-1, so a sum file using either proves little
+//! about files which someone else could have chosen. A minimum strength refuses, or only warns
+//! about, such algorithms; FIPS mode goes further and allows only those FIPS 180-4 specifies.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::{alg::Algorithm, error::Error};
+
+/// The least collision resistance, in bits, that an algorithm must offer; zero for no minimum.
+static MIN_STRENGTH: AtomicU32 = AtomicU32::new(0);
+
+/// Whether an algorithm short of the minimum strength is merely warned about.
+static WARN_WEAK: AtomicBool = AtomicBool::new(false);
+
+static FIPS: AtomicBool = AtomicBool::new(false);
+
+/// Refuse algorithms offering fewer than `bits` of collision resistance, or only warn about them.
+pub fn set_min_strength(bits: u32, warn: bool) {
+    MIN_STRENGTH.store(bits, Ordering::Relaxed);
+    WARN_WEAK.store(warn, Ordering::Relaxed);
+}
+
+/// Allow only FIPS-approved algorithms, including for comparisons.
+pub fn set_fips(fips: bool) {
+    FIPS.store(fips, Ordering::Relaxed);
+}
+
+pub fn is_fips() -> bool {
+    FIPS.load(Ordering::Relaxed)
+}
+
+/// Check that `algorithm` may be used to hash or verify files, warning on stderr if it falls
+/// short of the minimum strength but the policy is only to warn.
+pub fn check(algorithm: Algorithm) -> crate::Result<()> {
+    let min_strength = MIN_STRENGTH.load(Ordering::Relaxed);
+    let warn = WARN_WEAK.load(Ordering::Relaxed);
+
+    if let Some(warning) = judge(algorithm, is_fips(), min_strength, warn)? {
+        eprintln!("warning: {warning}");
+    }
+
+    Ok(())
+}
+
+/// Judge `algorithm` against a policy, returning the warning to give, if any.
+fn judge(
+    algorithm: Algorithm,
+    fips: bool,
+    min_strength: u32,
+    warn: bool,
+) -> crate::Result<Option<Error>> {
+    if fips && !algorithm.is_fips_approved() {
+        return Err(Error::NotApproved(algorithm));
+    }
+
+    if algorithm.strength() >= min_strength {
+        return Ok(None);
+    }
+
+    let error = Error::WeakAlgorithm {
+        algorithm,
+        min_strength,
+    };
+
+    if warn {
+        Ok(Some(error))
+    } else {
+        Err(error)
+    }
+}
+
+/// The algorithm used to compare files with one another, where the user doesn't choose one.
+pub fn comparison_algorithm() -> Algorithm {
+    if is_fips() {
+        Algorithm::Sha256
+    } else {
+        Algorithm::Blake3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::judge;
+    use crate::{alg::Algorithm, error::Error};
+
+    #[test]
+    fn fips_mode_refuses_unapproved_algorithms() {
+        for algorithm in Algorithm::ALL {
+            let verdict = judge(algorithm, true, 0, false);
+            if algorithm.is_fips_approved() {
+                assert!(matches!(verdict, Ok(None)), "{algorithm}");
+            } else {
+                assert!(matches!(verdict, Err(Error::NotApproved(a)) if a == algorithm));
+            }
+        }
+
+        // Being warned about weak algorithms doesn't let them through in FIPS mode.
+        assert!(judge(Algorithm::Md5, true, 128, true).is_err());
+    }
+
+    #[test]
+    fn weak_algorithms_are_refused_or_warned_about() {
+        assert!(matches!(judge(Algorithm::Md5, false, 0, false), Ok(None)));
+        assert!(matches!(
+            judge(Algorithm::Sha256, false, 128, false),
+            Ok(None)
+        ));
+
+        let refused = judge(Algorithm::Sha1, false, 128, false);
+        assert!(matches!(
+            refused,
+            Err(Error::WeakAlgorithm {
+                algorithm: Algorithm::Sha1,
+                min_strength: 128
+            })
+        ));
+
+        let warned = judge(Algorithm::Sha1, false, 128, true);
+        assert!(matches!(
+            warned,
+            Ok(Some(Error::WeakAlgorithm {
+                algorithm: Algorithm::Sha1,
+                min_strength: 128
+            }))
+        ));
+    }
+}
@@ -0,0 +1,424 @@
+//! Reporting progress through long runs on stderr.
+//!
+//! Totals come from the sizes of the files to be read, taken before any is opened. Bytes are
+//! counted as they're read, alongside the rate limit, and files as their results are reported. A
+//! thread of its own redraws the bar, or writes an event, at regular intervals.
+
+use std::{
+    fmt, fs,
+    io::{self, IsTerminal},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::alg::Algorithm;
+
+const BAR_INTERVAL: Duration = Duration::from_millis(200);
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+const BAR_WIDTH: usize = 30;
+
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static FILES: AtomicU64 = AtomicU64::new(0);
+
+static REPORTER: Mutex<Option<Reporter>> = Mutex::new(None);
+
+/// Whether the bar is on the screen.
+static DRAWN: Mutex<bool> = Mutex::new(false);
+
+/// Whether results printed on stdout land on the same screen as the bar.
+static SHARED_SCREEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// A bar, redrawn in place, when stderr is a terminal.
+    #[default]
+    Bar,
+    /// One JSON object per line.
+    Json,
+}
+
+impl fmt::Display for ProgressFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressFormat::Bar => f.write_str("bar"),
+            ProgressFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+impl FromStr for ProgressFormat {
+    type Err = UnknownProgressFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bar" => Ok(ProgressFormat::Bar),
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(UnknownProgressFormatError(s.into())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UnknownProgressFormatError(String);
+
+impl fmt::Display for UnknownProgressFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown progress format (expected bar or json): {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownProgressFormatError {}
+
+/// The amount of work to be done.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Totals {
+    pub files: u64,
+    /// The number of bytes to be read, unless only part of each file will be read.
+    pub bytes: Option<u64>,
+}
+
+/// Total up the sizes of `paths`. Files which can't be found count for nothing.
+pub fn scan<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Totals {
+    let mut totals = Totals {
+        files: 0,
+        bytes: Some(0),
+    };
+
+    for path in paths {
+        let len = fs::metadata(path).map_or(0, |meta| meta.len());
+        totals.files += 1;
+        totals.bytes = totals.bytes.map(|bytes| bytes + len);
+    }
+
+    totals
+}
+
+/// Account for `bytes` having been read, or found in the digest cache.
+pub fn add_bytes(bytes: u64) {
+    BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Account for a file whose result has been reported.
+pub fn file_done() {
+    FILES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Begin reporting progress toward `totals`, hashing with `algorithm` if the files are hashed at
+/// all. A bar is drawn only if stderr is a terminal.
+pub fn start(format: ProgressFormat, algorithm: Option<Algorithm>, totals: Totals) {
+    if format == ProgressFormat::Bar && !io::stderr().is_terminal() {
+        return;
+    }
+
+    BYTES.store(0, Ordering::Relaxed);
+    FILES.store(0, Ordering::Relaxed);
+    SHARED_SCREEN.store(io::stdout().is_terminal(), Ordering::Relaxed);
+
+    let (stop, stopped) = mpsc::channel::<()>();
+    let started = Instant::now();
+    let interval = match format {
+        ProgressFormat::Bar => BAR_INTERVAL,
+        ProgressFormat::Json => JSON_INTERVAL,
+    };
+
+    let thread = thread::spawn(move || loop {
+        match stopped.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => {
+                report(format, &Snapshot::take(algorithm, totals, started))
+            }
+            _ => return Snapshot::take(algorithm, totals, started),
+        }
+    });
+
+    *REPORTER.lock().unwrap() = Some(Reporter {
+        format,
+        stop,
+        thread,
+    });
+}
+
+/// Stop reporting progress, taking down the bar or writing a final event.
+pub fn finish() {
+    let Some(reporter) = REPORTER.lock().unwrap().take() else {
+        return;
+    };
+
+    drop(reporter.stop);
+    let Ok(last) = reporter.thread.join() else {
+        return;
+    };
+
+    match reporter.format {
+        ProgressFormat::Bar => drop(hide_from_stderr()),
+        ProgressFormat::Json => eprintln!("{}", last.to_json("done")),
+    }
+}
+
+/// Take the bar off the screen, if need be, until the guard is dropped, so that a line may be
+/// printed on stdout.
+pub fn hide() -> MutexGuard<'static, bool> {
+    hide_if(SHARED_SCREEN.load(Ordering::Relaxed))
+}
+
+/// Take the bar off the screen until the guard is dropped, so that a line may be printed on
+/// stderr.
+pub fn hide_from_stderr() -> MutexGuard<'static, bool> {
+    hide_if(true)
+}
+
+fn hide_if(clear: bool) -> MutexGuard<'static, bool> {
+    let mut drawn = DRAWN.lock().unwrap();
+    if clear && *drawn {
+        eprint!("\r\x1b[K");
+        *drawn = false;
+    }
+    drawn
+}
+
+struct Reporter {
+    format: ProgressFormat,
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<Snapshot>,
+}
+
+fn report(format: ProgressFormat, snapshot: &Snapshot) {
+    match format {
+        ProgressFormat::Bar => {
+            let mut drawn = DRAWN.lock().unwrap();
+            eprint!("\r\x1b[K{snapshot}");
+            *drawn = true;
+        }
+        ProgressFormat::Json => eprintln!("{}", snapshot.to_json("progress")),
+    }
+}
+
+/// Progress at a moment in time.
+#[derive(Debug, Serialize)]
+struct Snapshot {
+    algorithm: Option<&'static str>,
+    elapsed: f64,
+    files_done: u64,
+    files_total: u64,
+    bytes_done: u64,
+    bytes_total: Option<u64>,
+    bytes_per_second: f64,
+    /// Seconds until we're done, if we can tell.
+    eta: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'a str,
+    #[serde(flatten)]
+    snapshot: &'a Snapshot,
+}
+
+impl Snapshot {
+    fn take(algorithm: Option<Algorithm>, totals: Totals, started: Instant) -> Self {
+        Self::new(
+            algorithm,
+            totals,
+            FILES.load(Ordering::Relaxed),
+            BYTES.load(Ordering::Relaxed),
+            started.elapsed(),
+        )
+    }
+
+    fn new(
+        algorithm: Option<Algorithm>,
+        totals: Totals,
+        files_done: u64,
+        bytes_done: u64,
+        elapsed: Duration,
+    ) -> Self {
+        let elapsed = elapsed.as_secs_f64();
+        let bytes_per_second = if elapsed > 0.0 {
+            bytes_done as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        // Where we're reading whole files, the bytes left tell us how long is left; otherwise,
+        // the files left will have to do.
+        let eta = match totals.bytes {
+            Some(total) if bytes_done > 0 => {
+                Some(total.saturating_sub(bytes_done) as f64 / bytes_per_second)
+            }
+            None if files_done > 0 => {
+                let left = totals.files.saturating_sub(files_done) as f64;
+                Some(left * elapsed / files_done as f64)
+            }
+            _ => None,
+        };
+
+        Self {
+            algorithm: algorithm.map(Algorithm::name),
+            elapsed,
+            files_done,
+            files_total: totals.files,
+            bytes_done,
+            bytes_total: totals.bytes,
+            bytes_per_second,
+            eta,
+        }
+    }
+
+    fn fraction(&self) -> f64 {
+        let fraction = match self.bytes_total {
+            Some(0) => 1.0,
+            Some(total) => self.bytes_done as f64 / total as f64,
+            None if self.files_total == 0 => 1.0,
+            None => self.files_done as f64 / self.files_total as f64,
+        };
+        fraction.clamp(0.0, 1.0)
+    }
+
+    fn to_json(&self, event: &str) -> String {
+        let event = Event {
+            event,
+            snapshot: self,
+        };
+        serde_json::to_string(&event).expect("progress is always serializable")
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fraction = self.fraction();
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        write!(
+            f,
+            "{:>3}% [{}{}]",
+            (fraction * 100.0) as u32,
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled)
+        )?;
+
+        if let Some(total) = self.bytes_total {
+            write!(
+                f,
+                " {} / {} {}/s",
+                Bytes(self.bytes_done),
+                Bytes(total),
+                Bytes(self.bytes_per_second as u64)
+            )?;
+        }
+
+        write!(f, " {}/{} files", self.files_done, self.files_total)?;
+
+        match self.eta {
+            Some(eta) if eta.is_finite() => write!(f, " ETA {}", Eta(eta.ceil() as u64)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A number of bytes, in binary units.
+struct Bytes(u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = UNITS[0];
+        for next in &UNITS[1..] {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = next;
+        }
+
+        write!(f, "{value:.1} {unit}")
+    }
+}
+
+/// A number of seconds, as e.g. `38m12s`.
+struct Eta(u64);
+
+impl fmt::Display for Eta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (hours, minutes, seconds) = (self.0 / 3600, self.0 / 60 % 60, self.0 % 60);
+        match (hours, minutes) {
+            (0, 0) => write!(f, "{seconds}s"),
+            (0, _) => write!(f, "{minutes}m{seconds:02}s"),
+            _ => write!(f, "{hours}h{minutes:02}m"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Bytes, Eta, Snapshot, Totals};
+    use crate::alg::Algorithm;
+
+    #[test]
+    fn bar_shows_bytes_files_and_eta() {
+        let totals = Totals {
+            files: 10,
+            bytes: Some(400 << 20),
+        };
+        let snapshot = Snapshot::new(None, totals, 4, 100 << 20, Duration::from_secs(2));
+
+        assert_eq!(
+            " 25% [=======                       ] 100.0 MiB / 400.0 MiB 50.0 MiB/s 4/10 files ETA 6s",
+            snapshot.to_string()
+        );
+    }
+
+    #[test]
+    fn eta_falls_back_on_files() {
+        let totals = Totals {
+            files: 100,
+            bytes: None,
+        };
+        let snapshot = Snapshot::new(None, totals, 25, 0, Duration::from_secs(60));
+
+        assert_eq!(Some(180.0), snapshot.eta);
+        assert!(snapshot.to_string().ends_with("25/100 files ETA 3m00s"));
+    }
+
+    #[test]
+    fn events_are_json() {
+        let totals = Totals {
+            files: 1,
+            bytes: Some(10),
+        };
+        let algorithm = Some(Algorithm::Sha256);
+        let event = Snapshot::new(algorithm, totals, 1, 10, Duration::from_secs(1)).to_json("done");
+
+        let value: serde_json::Value = serde_json::from_str(&event).unwrap();
+        assert_eq!("done", value["event"]);
+        assert_eq!("sha256", value["algorithm"]);
+        assert_eq!(10, value["bytes_done"]);
+        assert_eq!(0.0, value["eta"]);
+    }
+
+    #[test]
+    fn units_are_readable() {
+        assert_eq!("512 B", Bytes(512).to_string());
+        assert_eq!("1.5 KiB", Bytes(1536).to_string());
+        assert_eq!("2.0 GiB", Bytes(2 << 30).to_string());
+        assert_eq!("1h01m", Eta(3661).to_string());
+    }
+}
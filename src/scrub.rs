@@ -0,0 +1,402 @@
+//! Incremental verification of large sum files.
+//!
+//! Each scrub verifies the entries that have gone longest without verification, stopping once
+//! its budget is spent. The time at which each entry was last verified is recorded in a state
+//! file kept beside the sum file, using the same append-only scheme as the digest cache, so an
+//! interrupted scrub loses nothing and the next one resumes where it left off.
+
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::{self, FromStr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::{
+    cache,
+    error::{Context, Operation},
+    hk::{self, Hashes, ReadPolicy},
+};
+
+static HEADER: &str = "# checksum scrub state v1";
+
+/// How much of the sum file a single scrub may verify.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limit {
+    pub budget: Option<Budget>,
+    pub fraction: Option<Fraction>,
+}
+
+pub struct Summary {
+    pub verified: usize,
+    pub total: usize,
+    pub has_failure: bool,
+    pub has_missing: bool,
+}
+
+/// Verify the least recently verified entries of the sum file at `path`.
+pub fn scrub(
+    path: &Path,
+    limit: Limit,
+    policy: ReadPolicy,
+    verbose: bool,
+) -> crate::Result<Summary> {
+    let mut hashes = Hashes::from_path(path, policy)?;
+    crate::policy::check(hashes.algorithm())?;
+    let state_path = state_path(path);
+    let mut state =
+        State::open(&state_path, hashes.names()).context(Operation::Open, &state_path)?;
+
+    // Entries that have never been verified sort first, as do entries which failed last time,
+    // since failures are never recorded.
+    hashes.sort_by_cached_key(|name| state.last_verified(name));
+
+    let total = hashes.names().count();
+    let count = limit
+        .fraction
+        .map_or(total, |fraction| fraction.of(total))
+        .min(total);
+    // A budget too long for the clock to represent is no limit at all.
+    let deadline = limit
+        .budget
+        .and_then(|budget| Instant::now().checked_add(budget.0));
+
+    let mut verified = 0;
+    let mut has_failure = false;
+    let mut has_missing = false;
+
+    for validation in hashes.verify().take(count) {
+        let validation = validation?;
+        verified += 1;
+
+        if validation.is_ok() {
+            state
+                .record(validation.name())
+                .context(Operation::Write, &state_path)?;
+            if verbose {
+                println!("{validation}");
+            }
+        } else {
+            if validation.is_missing() {
+                has_missing = true;
+            } else {
+                has_failure = true;
+            }
+            println!("{validation}");
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+    }
+
+    Ok(Summary {
+        verified,
+        total,
+        has_failure,
+        has_missing,
+    })
+}
+
+/// Where the state of scrubs of the sum file at `path` is kept.
+pub fn state_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".scrub");
+    path.with_file_name(name)
+}
+
+struct State {
+    last_verified: HashMap<PathBuf, u64>,
+    log: BufWriter<File>,
+}
+
+impl State {
+    fn open<'a>(path: &Path, names: impl IntoIterator<Item = &'a Path>) -> io::Result<Self> {
+        let (mut last_verified, lines) = match File::open(path) {
+            Ok(file) => read_state(file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e),
+        };
+
+        // Forget about entries that have since been removed from the sum file.
+        let names: HashSet<_> = names.into_iter().collect();
+        let known = last_verified.len();
+        last_verified.retain(|name, _| names.contains(name.as_path()));
+
+        if lines == 0 || lines > last_verified.len() * 2 || known != last_verified.len() {
+            write_state(path, &last_verified)?;
+        }
+
+        let log = OpenOptions::new().append(true).open(path)?;
+
+        Ok(Self {
+            last_verified,
+            log: BufWriter::new(log),
+        })
+    }
+
+    fn last_verified(&self, name: &Path) -> u64 {
+        self.last_verified.get(name).copied().unwrap_or_default()
+    }
+
+    fn record(&mut self, name: &Path) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        write_record(&mut self.log, now, name)?;
+        self.log.flush()?;
+        self.last_verified.insert(name.into(), now);
+        Ok(())
+    }
+}
+
+/// Names are escaped as in sum files, so that any name fits on one line.
+fn write_record(mut out: impl Write, timestamp: u64, name: &Path) -> io::Result<()> {
+    write!(out, "{timestamp}\t")?;
+    out.write_all(&hk::escape_name(name.as_os_str().as_encoded_bytes()))?;
+    out.write_all(b"\n")
+}
+
+fn parse_record(line: &[u8]) -> Option<(u64, PathBuf)> {
+    let tab = line.iter().position(|&u| u == b'\t')?;
+    let timestamp = str::from_utf8(&line[..tab]).ok()?.parse().ok()?;
+    let name = hk::path_from_bytes(&hk::unescape_name(&line[tab + 1..])?)?;
+    Some((timestamp, name))
+}
+
+fn read_state(file: File) -> io::Result<(HashMap<PathBuf, u64>, usize)> {
+    let mut last_verified = HashMap::new();
+    let mut lines = 0;
+
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        if line.starts_with(b"#") {
+            continue;
+        }
+
+        lines += 1;
+
+        if let Some((timestamp, name)) = parse_record(&line) {
+            last_verified.insert(name, timestamp);
+        }
+    }
+
+    Ok((last_verified, lines))
+}
+
+fn write_state(path: &Path, last_verified: &HashMap<PathBuf, u64>) -> io::Result<()> {
+    cache::rewrite(path, |out| {
+        writeln!(out, "{HEADER}")?;
+        for (name, &timestamp) in last_verified {
+            write_record(&mut *out, timestamp, name)?;
+        }
+        Ok(())
+    })
+}
+
+/// A length of time, written as e.g. `90s`, `45m`, `2h` or `1h30m`.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget(Duration);
+
+impl FromStr for Budget {
+    type Err = LimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut total = 0u64;
+        let mut digits = String::new();
+
+        for u in s.trim().chars() {
+            if u.is_ascii_digit() {
+                digits.push(u);
+                continue;
+            }
+
+            let scale = match u.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => return Err(LimitError::Budget(s.into())),
+            };
+
+            let amount: u64 = digits.parse().map_err(|_| LimitError::Budget(s.into()))?;
+            total = amount
+                .checked_mul(scale)
+                .and_then(|seconds| total.checked_add(seconds))
+                .ok_or_else(|| LimitError::Budget(s.into()))?;
+            digits.clear();
+        }
+
+        // A trailing number with no unit is taken as seconds.
+        if !digits.is_empty() {
+            total = digits
+                .parse::<u64>()
+                .ok()
+                .and_then(|seconds| total.checked_add(seconds))
+                .ok_or_else(|| LimitError::Budget(s.into()))?;
+        }
+
+        if total == 0 {
+            return Err(LimitError::Budget(s.into()));
+        }
+
+        Ok(Budget(Duration::from_secs(total)))
+    }
+}
+
+/// A share of the sum file, written as e.g. `10%` or `0.1`.
+#[derive(Clone, Copy, Debug)]
+pub struct Fraction(f64);
+
+impl Fraction {
+    fn of(self, total: usize) -> usize {
+        // Always make some progress, however small the fraction.
+        ((total as f64 * self.0).ceil() as usize).max(1)
+    }
+}
+
+impl FromStr for Fraction {
+    type Err = LimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let fraction = match s.strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f64>().map(|percent| percent / 100.0),
+            None => s.parse::<f64>(),
+        };
+
+        match fraction {
+            Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(Fraction(fraction)),
+            _ => Err(LimitError::Fraction(s.into())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LimitError {
+    Budget(String),
+    Fraction(String),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::Budget(s) => write!(f, "bad budget (expected e.g. 90s, 45m, 2h): {s}"),
+            LimitError::Fraction(s) => write!(f, "bad fraction (expected e.g. 10% or 0.1): {s}"),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use super::{
+        parse_record, read_state, scrub, state_path, write_record, Budget, Fraction, Limit, HEADER,
+    };
+    use crate::{alg::Algorithm, temp::TempDir};
+
+    #[test]
+    fn least_recently_verified_entries_go_first_and_scrubs_resume() {
+        let temp = TempDir::new("scrub").unwrap();
+        let dir = temp.path();
+
+        let mut text = String::new();
+        for name in ["a", "b", "c", "d"] {
+            fs::write(dir.join(name), name).unwrap();
+            let hash = Algorithm::Sha256.hash(dir.join(name)).unwrap();
+            text += &format!("{hash}  {name}\n");
+        }
+        let manifest = dir.join("SHA256SUMS");
+        fs::write(&manifest, text).unwrap();
+
+        // b was verified longest ago, and d never at all.
+        let state = state_path(&manifest);
+        fs::write(&state, format!("{HEADER}\n300\ta\n100\tb\n200\tc\n")).unwrap();
+        let read = || read_state(File::open(&state).unwrap()).unwrap().0;
+
+        let limit = Limit {
+            budget: None,
+            fraction: Some(Fraction(0.25)),
+        };
+
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            let before = read();
+            let summary = scrub(&manifest, limit, Default::default(), false).unwrap();
+            assert_eq!((1, 4), (summary.verified, summary.total));
+            assert!(!summary.has_failure && !summary.has_missing);
+
+            let after = read();
+            let (name, _) = after
+                .iter()
+                .find(|(name, timestamp)| before.get(*name) != Some(timestamp))
+                .unwrap();
+            order.push(name.clone());
+        }
+
+        let expected: Vec<PathBuf> = ["d", "b", "c", "a"].map(PathBuf::from).into();
+        assert_eq!(expected, order);
+    }
+
+    #[test]
+    fn budgets_parse() {
+        let budget = |s: &str| s.parse::<Budget>().map(|budget| budget.0).ok();
+
+        assert_eq!(Some(Duration::from_secs(7200)), budget("2h"));
+        assert_eq!(Some(Duration::from_secs(5400)), budget("1h30m"));
+        assert_eq!(Some(Duration::from_secs(90)), budget("90"));
+        assert_eq!(None, budget("2x"));
+        assert_eq!(None, budget("h"));
+        assert_eq!(None, budget("0s"));
+        assert_eq!(None, budget("99999999999999999d"));
+        assert_eq!(None, budget("18446744073709551615s1s"));
+        assert_eq!(None, budget("18446744073709551616"));
+
+        // Too long for a deadline, but not for a duration.
+        assert_eq!(
+            Some(Duration::from_secs(u64::MAX)),
+            budget("18446744073709551615")
+        );
+    }
+
+    #[test]
+    fn fractions_parse() {
+        let fraction = |s: &str| s.parse::<Fraction>().map(|fraction| fraction.0).ok();
+
+        assert_eq!(Some(0.1), fraction("10%"));
+        assert_eq!(Some(0.25), fraction("0.25"));
+        assert_eq!(None, fraction("0%"));
+        assert_eq!(None, fraction("150%"));
+    }
+
+    #[test]
+    fn fractions_always_make_progress() {
+        let fraction: Fraction = "1%".parse().unwrap();
+        assert_eq!(1, fraction.of(10));
+        assert_eq!(10, fraction.of(1000));
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let name = Path::new("odd\\name\nhere");
+        let mut buf = Vec::new();
+        write_record(&mut buf, 1700000000, name).unwrap();
+
+        let (timestamp, parsed) = parse_record(buf.strip_suffix(b"\n").unwrap()).unwrap();
+        assert_eq!(1700000000, timestamp);
+        assert_eq!(name, parsed);
+    }
+}
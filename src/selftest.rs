@@ -0,0 +1,204 @@
+//! Checking each algorithm against published known answers.
+//!
+//! Every vector is hashed through each path by which checksum may hash data: in memory, in
+//! pieces, from a file read in one go or through the double-buffered reader, in a batch of files,
+//! and, for blake3, through a mapping of the file hashed on one core or on all of them. Any
+//! discrepancy is reported, along with the instructions each implementation chose on this machine.
+
+use std::{
+    fs::{self, File},
+    io::{self, IsTerminal},
+    path::Path,
+};
+
+use owo_colors::OwoColorize;
+
+use crate::{
+    alg::Algorithm,
+    error::{Context, Operation},
+    hash::{self, ByteSize},
+    temp::TempDir,
+};
+
+/// Files longer than this are read through the double-buffered reader during the self-test, so
+/// that both ways of reading a file are tested by vectors of modest length.
+const BUFFER_SIZE: ByteSize = ByteSize::new(4096);
+
+/// The amount fed to a digest at a time when hashing in pieces: not a multiple of any block size.
+const PIECE: usize = 61;
+
+enum Input {
+    Text(&'static [u8]),
+    /// One byte, repeated.
+    Repeat(u8, usize),
+    /// The bytes 0 through 250, repeated, as used by the BLAKE3 test vectors.
+    Pattern(usize),
+}
+
+impl Input {
+    fn bytes(&self) -> Vec<u8> {
+        match *self {
+            Input::Text(text) => text.to_vec(),
+            Input::Repeat(u, len) => vec![u; len],
+            Input::Pattern(len) => (0..len).map(|i| (i % 251) as u8).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for Input {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Input::Text(text) => write!(f, "{:?}", String::from_utf8_lossy(text)),
+            Input::Repeat(u, len) => write!(f, "{len} x {:?}", u as char),
+            Input::Pattern(len) => write!(f, "{len} bytes of 0..251"),
+        }
+    }
+}
+
+const ABC_448: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+const ABC_896: &[u8] = b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+
+/// Known answers from RFC 1321 (md5), FIPS 180-2 (sha1, sha256, sha512) and the BLAKE3 reference
+/// test vectors.
+static VECTORS: &[(Algorithm, Input, &str)] = &[
+    (Algorithm::Blake3, Input::Pattern(0), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+    (Algorithm::Blake3, Input::Pattern(1), "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"),
+    (Algorithm::Blake3, Input::Pattern(1023), "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11"),
+    (Algorithm::Blake3, Input::Pattern(1024), "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+    (Algorithm::Blake3, Input::Pattern(1025), "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+    (Algorithm::Blake3, Input::Pattern(8193), "bab6c09cb8ce8cf459261398d2e7aef35700bf488116ceb94a36d0f5f1b7bc3b"),
+    (Algorithm::Blake3, Input::Pattern(102400), "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"),
+    (Algorithm::Md5, Input::Text(b""), "d41d8cd98f00b204e9800998ecf8427e"),
+    (Algorithm::Md5, Input::Text(b"abc"), "900150983cd24fb0d6963f7d28e17f72"),
+    (Algorithm::Md5, Input::Text(b"message digest"), "f96b697d7cb7938d525a2f31aaf161d0"),
+    (Algorithm::Md5, Input::Repeat(b'a', 1_000_000), "7707d6ae4e027c70eea2a935c2296f21"),
+    (Algorithm::Sha1, Input::Text(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+    (Algorithm::Sha1, Input::Text(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d"),
+    (Algorithm::Sha1, Input::Text(ABC_448), "84983e441c3bd26ebaae4aa1f95129e5e54670f1"),
+    (Algorithm::Sha1, Input::Repeat(b'a', 1_000_000), "34aa973cd4c4daa4f61eeb2bdbad27316534016f"),
+    (Algorithm::Sha256, Input::Text(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+    (Algorithm::Sha256, Input::Text(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+    (Algorithm::Sha256, Input::Text(ABC_448), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+    (Algorithm::Sha256, Input::Repeat(b'a', 1_000_000), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"),
+    (Algorithm::Sha512, Input::Text(b""), "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"),
+    (Algorithm::Sha512, Input::Text(b"abc"), "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"),
+    (Algorithm::Sha512, Input::Text(ABC_896), "8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909"),
+    (Algorithm::Sha512, Input::Repeat(b'a', 1_000_000), "e718483d0ce769644e2e42c7bc15b4638e1f98b13b2044285632a803afa973ebde0ff244877ea60a4cb0432ce577c31beb009c5c2c49aa2e4eadb217ad8cc09b"),
+];
+
+/// Run every known-answer vector through every path, returning `true` if all of them agree.
+pub fn selftest(verbose: bool) -> crate::Result<bool> {
+    let colorize = io::stdout().is_terminal();
+    hash::set_buffer_size(BUFFER_SIZE);
+
+    let dir = TempDir::new("selftest").context(Operation::Write, std::env::temp_dir())?;
+    let mut passed = true;
+
+    for algorithm in Algorithm::ALL {
+        let vectors: Vec<_> = VECTORS
+            .iter()
+            .filter(|(other, ..)| *other == algorithm)
+            .collect();
+
+        let mut paths = Vec::new();
+        for (n, (_, input, _)) in vectors.iter().enumerate() {
+            let path = dir.path().join(format!("{algorithm}-{n}"));
+            fs::write(&path, input.bytes()).context(Operation::Write, &path)?;
+            paths.push(path);
+        }
+
+        let mut failures = Vec::new();
+        let batch = hash::hash_all(algorithm, &paths);
+
+        for (((_, input, expected), path), batched) in vectors.iter().zip(&paths).zip(batch) {
+            let data = input.bytes();
+            let mut results = vec![
+                (
+                    "in memory",
+                    Ok(hash_bytes(algorithm, &data, data.len().max(1))),
+                ),
+                ("in pieces", Ok(hash_bytes(algorithm, &data, PIECE))),
+                ("from a file", algorithm.hash_uncached(path)),
+                ("in a batch", batched),
+            ];
+            if algorithm == Algorithm::Blake3 {
+                results.push(("mapped", hash_mapped(path, false)));
+                results.push(("mapped, in parallel", hash_mapped(path, true)));
+            }
+
+            for (method, actual) in results {
+                let actual = actual.context(Operation::Read, path)?;
+                if actual != *expected {
+                    failures.push(format!(
+                        "{input} ({method}): expected {expected}, got {actual}"
+                    ));
+                }
+            }
+        }
+
+        let name = algorithm.name();
+        let backend = algorithm.acceleration().unwrap_or("portable");
+        let summary = format!("{name:<8}{backend:<10}{} vectors", vectors.len());
+
+        if failures.is_empty() {
+            if colorize {
+                println!("{} {summary}", "ok".green());
+            } else {
+                println!("ok {summary}");
+            }
+        } else {
+            passed = false;
+            if colorize {
+                println!("{} {summary}", "FAIL".red());
+            } else {
+                println!("FAIL {summary}");
+            }
+            for failure in failures {
+                println!("  {failure}");
+            }
+        }
+
+        if verbose {
+            for (_, input, _) in &vectors {
+                println!("  {input}");
+            }
+        }
+    }
+
+    Ok(passed)
+}
+
+fn hash_bytes(algorithm: Algorithm, data: &[u8], piece: usize) -> String {
+    let mut digest = algorithm.digest();
+    for piece in data.chunks(piece) {
+        digest.update(piece);
+    }
+    hash::fmt_hex(&digest.finalize())
+}
+
+fn hash_mapped(path: &Path, parallel: bool) -> io::Result<String> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    // An empty file can't be mapped, so there's nothing to test.
+    if len == 0 {
+        return Ok(blake3::hash(&[]).to_hex().to_string());
+    }
+
+    hash::hash_mapped(&file, len, parallel)?
+        .ok_or_else(|| io::Error::other("file changed while being hashed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_bytes, PIECE, VECTORS};
+
+    #[test]
+    fn vectors_pass_in_memory() {
+        for (algorithm, input, expected) in VECTORS {
+            let data = input.bytes();
+            assert_eq!(*expected, hash_bytes(*algorithm, &data, PIECE), "{input}");
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Digests stored in extended attributes.
+//!
+//! Each file is tagged with `user.checksum.<alg>` and `user.checksum.<alg>.ts`, the latter
+//! recording the file's mtime at the time it was hashed with that algorithm. For sha256, the same
+//! values are also written as `user.shatag.sha256` and `user.shatag.ts` so that the tags are
+//! understood by shatag, and shatag's own tags are accepted when verifying.
+
+use std::{
+    fmt, fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use owo_colors::OwoColorize;
+
+use crate::{alg::Algorithm, fmt::DisplayPath};
+
+static NAMESPACES: &[&str] = &["user.checksum", "user.shatag"];
+
+#[derive(Clone, Debug)]
+pub struct Tag {
+    pub hash: String,
+    pub mtime: Timestamp,
+}
+
+/// Hash each file and store the result in its extended attributes.
+pub fn write_all(
+    files: impl IntoIterator<Item = PathBuf>,
+    algorithm: Algorithm,
+    verbose: bool,
+) -> crate::Result<()> {
+    for file in files {
+        crate::error::recover(&file, write_one(&file, algorithm, verbose))?;
+    }
+
+    Ok(())
+}
+
+fn write_one(file: &Path, algorithm: Algorithm, verbose: bool) -> io::Result<()> {
+    let mtime = Timestamp::of(&fs::metadata(file)?)?;
+    let hash = algorithm.hash(file)?;
+
+    // A file modified while we were reading it would be tagged with a digest matching
+    // neither version, and would then be reported as corrupt on verification.
+    if !mtime.matches(&Timestamp::of(&fs::metadata(file)?)?) {
+        eprintln!("skipped {}: modified while hashing", file.display());
+        return Ok(());
+    }
+
+    if verbose {
+        println!("{hash}  {}", DisplayPath(file));
+    }
+
+    write(file, algorithm, &Tag { hash, mtime })
+}
+
+/// Verify each file against the digest stored in its extended attributes, returning true if any
+/// file is corrupt.
+pub fn verify_all(
+    files: impl IntoIterator<Item = PathBuf>,
+    algorithm: Algorithm,
+    verbose: bool,
+) -> crate::Result<bool> {
+    let colorize = io::stdout().is_terminal();
+    let mut has_failure = false;
+
+    for file in files {
+        let Some(status) = crate::error::recover(&file, verify_one(&file, algorithm))? else {
+            continue;
+        };
+
+        match status {
+            Status::Ok if !verbose => {}
+            Status::Corrupt => {
+                has_failure = true;
+                print_status(status, &file, colorize);
+            }
+            _ => print_status(status, &file, colorize),
+        }
+    }
+
+    Ok(has_failure)
+}
+
+fn verify_one(file: &Path, algorithm: Algorithm) -> io::Result<Status> {
+    let Some(tag) = read(file, algorithm)? else {
+        return Ok(Status::Untagged);
+    };
+
+    let before = Timestamp::of(&fs::metadata(file)?)?;
+    let actual = algorithm.hash(file)?;
+    let after = Timestamp::of(&fs::metadata(file)?)?;
+
+    Ok(classify(&tag, &actual, &before, &after))
+}
+
+/// Classify a file tagged with `tag` whose content hashed to `actual`, given its mtime `before`
+/// and `after` hashing it.
+fn classify(tag: &Tag, actual: &str, before: &Timestamp, after: &Timestamp) -> Status {
+    if tag.hash.eq_ignore_ascii_case(actual) {
+        Status::Ok
+    } else if tag.mtime.matches(before) && before.matches(after) {
+        // Only a file untouched since it was tagged, and while we read it, can be corrupt.
+        Status::Corrupt
+    } else {
+        Status::Modified
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Modified,
+    Corrupt,
+    Untagged,
+}
+
+fn print_status(status: Status, path: &Path, colorize: bool) {
+    let path = DisplayPath(path);
+
+    if !colorize {
+        let status = match status {
+            Status::Ok => "OK",
+            Status::Modified => "MODIFIED",
+            Status::Corrupt => "CORRUPT",
+            Status::Untagged => "UNTAGGED",
+        };
+        println!("{status} {path}");
+        return;
+    }
+
+    match status {
+        Status::Ok => println!("{} {path}", "OK".bright_green()),
+        Status::Modified => println!("{} {path}", "MODIFIED".yellow()),
+        Status::Corrupt => println!("{} {path}", "CORRUPT".red()),
+        Status::Untagged => println!("{} {path}", "UNTAGGED".yellow()),
+    }
+}
+
+/// Read the tag for `algorithm` from `path`, if the file has one.
+pub fn read(path: &Path, algorithm: Algorithm) -> io::Result<Option<Tag>> {
+    let name = algorithm.name();
+
+    for namespace in NAMESPACES {
+        let hash = sys::get(path, &format!("{namespace}.{name}"))?;
+        let mtime = sys::get(path, &timestamp_name(namespace, name))?;
+
+        if let (Some(hash), Some(mtime)) = (hash, mtime) {
+            let mtime = mtime.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed timestamp attribute")
+            })?;
+            return Ok(Some(Tag { hash, mtime }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Tag `path` with a digest computed by `algorithm`.
+pub fn write(path: &Path, algorithm: Algorithm, tag: &Tag) -> io::Result<()> {
+    let name = algorithm.name();
+    let namespaces = match algorithm {
+        Algorithm::Sha256 => NAMESPACES,
+        _ => &NAMESPACES[..1],
+    };
+
+    for namespace in namespaces {
+        sys::set(path, &format!("{namespace}.{name}"), &tag.hash)?;
+        sys::set(
+            path,
+            &timestamp_name(namespace, name),
+            &tag.mtime.to_string(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The attribute holding the mtime of a file when it was hashed with the algorithm `name`.
+///
+/// Our own tags keep a timestamp per algorithm, since each algorithm's digest may have been taken
+/// at a different time; shatag's keep one, as only sha256 is ever written there.
+fn timestamp_name(namespace: &str, name: &str) -> String {
+    if namespace == NAMESPACES[0] {
+        format!("{namespace}.{name}.ts")
+    } else {
+        format!("{namespace}.ts")
+    }
+}
+
+/// A modification time, as stored alongside a digest.
+///
+/// Timestamps are written as seconds with nine digits of fractional nanoseconds. Other tools
+/// store fewer digits, so a timestamp remembers how many it was given and is compared with
+/// others only to that precision.
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamp {
+    secs: i64,
+    nanos: u32,
+    digits: u32,
+}
+
+impl Timestamp {
+    pub fn of(meta: &fs::Metadata) -> io::Result<Self> {
+        let mtime = meta.modified()?;
+        let (secs, nanos) = match mtime.duration_since(std::time::UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+            Err(e) => {
+                let before = e.duration();
+                match before.subsec_nanos() {
+                    0 => (-(before.as_secs() as i64), 0),
+                    n => (-(before.as_secs() as i64) - 1, 1_000_000_000 - n),
+                }
+            }
+        };
+
+        Ok(Self {
+            secs,
+            nanos,
+            digits: 9,
+        })
+    }
+
+    /// True if `other` falls within the precision of this timestamp.
+    pub fn matches(&self, other: &Timestamp) -> bool {
+        let scale = 10u32.pow(9 - self.digits.min(other.digits));
+        self.secs == other.secs && self.nanos / scale == other.nanos / scale
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:09}", self.secs, self.nanos)
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (secs, fraction) = s.trim().split_once('.').unwrap_or((s.trim(), ""));
+        let secs = secs.parse().map_err(|_| ())?;
+
+        if !fraction.bytes().all(|u| u.is_ascii_digit()) {
+            return Err(());
+        }
+
+        // Anything beyond nanosecond precision is noise.
+        let fraction = &fraction[..fraction.len().min(9)];
+
+        let digits = fraction.len() as u32;
+        let nanos = match fraction {
+            "" => 0,
+            fraction => fraction.parse::<u32>().map_err(|_| ())? * 10u32.pow(9 - digits),
+        };
+
+        Ok(Self {
+            secs,
+            nanos,
+            digits,
+        })
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{io, path::Path};
+
+    pub fn get(path: &Path, name: &str) -> io::Result<Option<String>> {
+        let value = xattr::get(path, name)?;
+        Ok(value.map(|value| String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    pub fn set(path: &Path, name: &str, value: &str) -> io::Result<()> {
+        xattr::set(path, name, value.as_bytes())
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::{io, path::Path};
+
+    pub fn get(_path: &Path, _name: &str) -> io::Result<Option<String>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn set(_path: &Path, _name: &str, _value: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, Status, Tag, Timestamp};
+    use crate::alg::Algorithm;
+
+    #[test]
+    fn changes_are_classified_by_mtime() {
+        let tagged: Timestamp = "1700000000.000000001".parse().unwrap();
+        let later: Timestamp = "1700000005.000000000".parse().unwrap();
+        let tag = Tag {
+            hash: "ABC123".into(),
+            mtime: tagged,
+        };
+
+        assert_eq!(Status::Ok, classify(&tag, "abc123", &tagged, &tagged));
+        assert_eq!(Status::Ok, classify(&tag, "abc123", &later, &later));
+        assert_eq!(Status::Corrupt, classify(&tag, "def456", &tagged, &tagged));
+        assert_eq!(Status::Modified, classify(&tag, "def456", &later, &later));
+
+        // A file written while we were reading it hasn't been shown to be corrupt.
+        assert_eq!(Status::Modified, classify(&tag, "def456", &tagged, &later));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn each_algorithm_keeps_its_own_timestamp() {
+        use std::{fs, time::Duration};
+
+        use super::{verify_one, write_one};
+        use crate::temp::TempDir;
+
+        let temp = TempDir::new("xattr").unwrap();
+        let file = temp.path().join("f.txt");
+        fs::write(&file, "one").unwrap();
+
+        // Not every filesystem holding temporary files supports extended attributes.
+        if write_one(&file, Algorithm::Sha256, false).is_err() {
+            return;
+        }
+
+        // Editing a file, then tagging it with another algorithm, is no sign of corruption.
+        let tagged = fs::metadata(&file).unwrap().modified().unwrap();
+        fs::write(&file, "two").unwrap();
+        let edited = fs::File::options().write(true).open(&file).unwrap();
+        edited
+            .set_modified(tagged + Duration::from_secs(5))
+            .unwrap();
+        write_one(&file, Algorithm::Blake3, false).unwrap();
+
+        assert_eq!(
+            Status::Modified,
+            verify_one(&file, Algorithm::Sha256).unwrap()
+        );
+        assert_eq!(Status::Ok, verify_one(&file, Algorithm::Blake3).unwrap());
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let ts: Timestamp = "1700000000.000000042".parse().unwrap();
+        assert_eq!("1700000000.000000042", ts.to_string());
+    }
+
+    #[test]
+    fn timestamps_compare_at_lowest_precision() {
+        let full: Timestamp = "1353592591.560412345".parse().unwrap();
+        let short: Timestamp = "1353592591.5604".parse().unwrap();
+        let other: Timestamp = "1353592591.5605".parse().unwrap();
+
+        assert!(full.matches(&short));
+        assert!(short.matches(&full));
+        assert!(!full.matches(&other));
+    }
+
+    #[test]
+    fn malformed_timestamps_are_rejected() {
+        assert!("".parse::<Timestamp>().is_err());
+        assert!("12.3x".parse::<Timestamp>().is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Scratch directories, removed once we're done with them.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A directory of our own under the system's temporary directory, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a fresh directory which only we may enter, named for `purpose`.
+    ///
+    /// The name is unpredictable and the directory must not already exist, so that nobody else
+    /// can prepare it in advance and swap its contents out from under us.
+    pub fn new(purpose: &str) -> io::Result<Self> {
+        let temp = std::env::temp_dir();
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+        for _ in 0..16 {
+            let path = temp.join(format!("checksum-{purpose}-{:016x}", random()));
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self(path)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::ErrorKind::AlreadyExists.into())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+/// A number nobody else can guess, drawn from the keys std seeds its hash maps with.
+fn random() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TempDir;
+
+    #[test]
+    fn temp_dirs_are_fresh_and_private() {
+        let first = TempDir::new("test").unwrap();
+        let second = TempDir::new("test").unwrap();
+        assert_ne!(first.path(), second.path());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(first.path())
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(0, mode & 0o077);
+        }
+
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
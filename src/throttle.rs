@@ -0,0 +1,162 @@
+//! Keeping out of the way of other work on the same machine.
+//!
+//! Every read of file contents is charged against a single rate limit, shared by all threads, and
+//! the process can be asked to take only whatever CPU time and disk bandwidth nothing else wants.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+static THROTTLE: Throttle = Throttle::new();
+
+/// Limit the rate at which files are read, in bytes per second.
+pub fn set_rate_limit(rate: u64) {
+    THROTTLE.rate.store(rate, Ordering::Relaxed);
+}
+
+pub fn is_limited() -> bool {
+    THROTTLE.rate.load(Ordering::Relaxed) != 0
+}
+
+/// Account for `bytes` just read, waiting as long as it takes to keep within the rate limit.
+pub fn consume(bytes: usize) {
+    THROTTLE.consume(bytes);
+}
+
+struct Throttle {
+    /// Bytes per second, or zero for no limit.
+    rate: AtomicU64,
+    /// The time at which the next read may go ahead.
+    next: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    const fn new() -> Self {
+        Self {
+            rate: AtomicU64::new(0),
+            next: Mutex::new(None),
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 || bytes == 0 {
+            return;
+        }
+
+        // Each read books the time it would take at the limit, after any reads already booked.
+        // Time left unused isn't saved up, so an idle spell doesn't lead to a burst.
+        let cost = Duration::from_secs_f64(bytes as f64 / rate as f64);
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = next.map_or(now, |next| next.max(now));
+            *next = Some(start + cost);
+            start
+        };
+
+        thread::sleep(start.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Run at the lowest CPU priority and, where supported, in the idle I/O scheduling class.
+///
+/// Threads started afterward inherit both, so this should be called before any work begins.
+pub fn be_nice() {
+    if let Err(e) = sys::lower_cpu_priority() {
+        eprintln!("warning: cannot lower CPU priority: {e}");
+    }
+
+    if let Err(e) = sys::lower_io_priority() {
+        eprintln!("warning: cannot lower I/O priority: {e}");
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+
+    /// The niceness of a process which runs only when nothing else wants to.
+    const LOWEST_PRIORITY: libc::c_int = 19;
+
+    pub fn lower_cpu_priority() -> io::Result<()> {
+        // SAFETY: setpriority only reads its arguments.
+        match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOWEST_PRIORITY) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn lower_io_priority() -> io::Result<()> {
+        // From linux/ioprio.h.
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+        let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+
+        // SAFETY: ioprio_set only reads its arguments.
+        match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    // Other systems have no I/O scheduling classes, and a low CPU priority is the best we can do.
+    #[cfg(not(target_os = "linux"))]
+    pub fn lower_io_priority() -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+
+    pub fn lower_cpu_priority() -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn lower_io_priority() -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::Ordering,
+        time::{Duration, Instant},
+    };
+
+    use super::Throttle;
+
+    #[test]
+    fn reads_are_held_to_the_limit() {
+        let throttle = Throttle::new();
+        throttle.rate.store(1_000_000, Ordering::Relaxed);
+
+        let start = Instant::now();
+        for _ in 0..5 {
+            throttle.consume(100_000);
+        }
+
+        // The first read goes ahead at once; each of the other four waits its turn.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn no_limit_means_no_waiting() {
+        let throttle = Throttle::new();
+
+        let start = Instant::now();
+        throttle.consume(usize::MAX);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
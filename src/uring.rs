@@ -0,0 +1,278 @@
+//! Reading many files at once through io_uring.
+//!
+//! Rather than opening and reading one file at a time, we keep a fixed number of files in flight,
+//! each with a single open or read outstanding, and feed each buffer to its file's digest as it
+//! completes. Small files in particular benefit, since their opens and reads overlap.
+
+use std::{
+    cell::RefCell,
+    ffi::CString,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+};
+
+use digest::DynDigest;
+use io_uring::{cqueue, opcode, types, IoUring, Probe};
+
+use crate::alg::Algorithm;
+
+/// The number of files in flight at once.
+const QUEUE_DEPTH: u32 = 64;
+
+const READ_SIZE: u32 = 256 * 1024;
+
+/// The result for each file, or `None` for a file we didn't finish with.
+type Results = Vec<Option<io::Result<String>>>;
+
+thread_local! {
+    /// Each thread keeps its ring, and the buffers which go with it, for reuse. `None` means that
+    /// we've yet to try to set up a ring; `Some(None)` means that we tried and failed.
+    static RING: RefCell<Option<Option<Ring>>> = const { RefCell::new(None) };
+}
+
+struct Ring {
+    ring: IoUring,
+    // One buffer per slot, allocated on first use.
+    buffers: Vec<Vec<u8>>,
+}
+
+impl Ring {
+    fn new() -> Option<Self> {
+        let ring = IoUring::new(QUEUE_DEPTH).ok()?;
+
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe).ok()?;
+        if !probe.is_supported(opcode::OpenAt::CODE) || !probe.is_supported(opcode::Read::CODE) {
+            return None;
+        }
+
+        Some(Self {
+            ring,
+            buffers: (0..QUEUE_DEPTH).map(|_| Vec::new()).collect(),
+        })
+    }
+}
+
+/// Hash each of `paths` with `algorithm`, or return `None` if the kernel doesn't support
+/// io_uring, or forbids its use.
+///
+/// Should the ring fail part way through, the files it hadn't finished with are given as `None`,
+/// to be hashed some other way.
+pub fn hash_all<P: AsRef<Path>>(paths: &[P], algorithm: Algorithm) -> Option<Results> {
+    RING.with_borrow_mut(|state| {
+        let ring = state.get_or_insert_with(Ring::new).as_mut()?;
+        match Batch::new(paths.len()).run(ring, paths, algorithm) {
+            Ok(results) => Some(results),
+            Err(results) => {
+                // The kernel may still write into the ring's buffers, so they must never be
+                // freed, and the ring can't be trusted with another batch.
+                std::mem::forget(state.replace(None));
+                Some(results)
+            }
+        }
+    })
+}
+
+struct Slot {
+    index: usize,
+    path: CString,
+    fd: Option<OwnedFd>,
+    offset: u64,
+    digest: Box<dyn DynDigest + Send>,
+}
+
+struct Batch {
+    results: Results,
+    // Slots are never moved while an operation is in flight, since the kernel holds pointers
+    // into their paths.
+    slots: Vec<Option<Slot>>,
+}
+
+impl Batch {
+    fn new(len: usize) -> Self {
+        Self {
+            results: (0..len).map(|_| None).collect(),
+            slots: (0..QUEUE_DEPTH).map(|_| None).collect(),
+        }
+    }
+
+    fn run<P: AsRef<Path>>(
+        mut self,
+        Ring { ring, buffers }: &mut Ring,
+        paths: &[P],
+        algorithm: Algorithm,
+    ) -> Result<Results, Results> {
+        let mut next = 0;
+        let mut in_flight = 0;
+
+        loop {
+            // Start on as many files as there are free slots.
+            for slot in 0..self.slots.len() {
+                if self.slots[slot].is_some() || next == paths.len() {
+                    continue;
+                }
+
+                let index = next;
+                next += 1;
+
+                let Ok(path) = CString::new(paths[index].as_ref().as_os_str().as_bytes()) else {
+                    self.results[index] = Some(Err(io::ErrorKind::InvalidInput.into()));
+                    continue;
+                };
+
+                let entry = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), path.as_ptr())
+                    .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+                    .build()
+                    .user_data(slot as u64);
+
+                self.slots[slot] = Some(Slot {
+                    index,
+                    path,
+                    fd: None,
+                    offset: 0,
+                    digest: algorithm.digest(),
+                });
+
+                // SAFETY: the path lives in its slot until the open completes. There is room in
+                // the queue, since each slot has at most one operation outstanding.
+                unsafe { ring.submission().push(&entry) }.expect("queue has room");
+                in_flight += 1;
+            }
+
+            if in_flight == 0 {
+                break;
+            }
+
+            if let Err(e) = ring.submit_and_wait(1) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                // Paths for opens in flight must never be freed, either.
+                std::mem::forget(self.slots);
+                return Err(self.results);
+            }
+
+            let completed: Vec<cqueue::Entry> = ring.completion().collect();
+            for entry in completed {
+                in_flight -= 1;
+                let slot = entry.user_data() as usize;
+                let buffer = &mut buffers[slot];
+                if buffer.is_empty() {
+                    *buffer = vec![0; READ_SIZE as usize];
+                }
+
+                if let Some(read) = self.complete(slot, entry.result(), buffer) {
+                    // SAFETY: each slot's buffer lives as long as the ring, and is used for only
+                    // one read at a time.
+                    unsafe { ring.submission().push(&read) }.expect("queue has room");
+                    in_flight += 1;
+                }
+            }
+        }
+
+        debug_assert!(
+            self.results.iter().all(Option::is_some),
+            "every file is hashed"
+        );
+        Ok(self.results)
+    }
+
+    /// Handle the completion of the operation in flight for `slot`, returning the next read to
+    /// submit, if any.
+    fn complete(
+        &mut self,
+        slot: usize,
+        result: i32,
+        buffer: &mut [u8],
+    ) -> Option<io_uring::squeue::Entry> {
+        let state = self.slots[slot].as_mut().expect("slot is in use");
+
+        if result < 0 && -result != libc::EINTR && -result != libc::EAGAIN {
+            let state = self.slots[slot].take().unwrap();
+            self.results[state.index] = Some(Err(io::Error::from_raw_os_error(-result)));
+            return None;
+        }
+
+        match &state.fd {
+            // The open completed.
+            None if result >= 0 => {
+                // SAFETY: a successful open returns a descriptor which nothing else owns.
+                state.fd = Some(unsafe { OwnedFd::from_raw_fd(result) });
+            }
+            // The open was interrupted and must be retried.
+            None => {
+                let open = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), state.path.as_ptr())
+                    .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+                    .build()
+                    .user_data(slot as u64);
+                return Some(open);
+            }
+            // The end of the file.
+            Some(_) if result == 0 => {
+                let state = self.slots[slot].take().unwrap();
+                let mut digest = state.digest;
+                let mut output = vec![0; digest.output_size()];
+                digest
+                    .finalize_into_reset(&mut output)
+                    .expect("buffer fits digest");
+                self.results[state.index] = Some(Ok(crate::hash::fmt_hex(&output)));
+                return None;
+            }
+            Some(_) if result > 0 => {
+                crate::throttle::consume(result as usize);
+                crate::progress::add_bytes(result as u64);
+                state.digest.update(&buffer[..result as usize]);
+                state.offset += result as u64;
+            }
+            // The read was interrupted and must be retried.
+            Some(_) => {}
+        }
+
+        let fd = state.fd.as_ref().expect("file is open").as_raw_fd();
+        let read = opcode::Read::new(types::Fd(fd), buffer.as_mut_ptr(), READ_SIZE)
+            .offset(state.offset)
+            .build()
+            .user_data(slot as u64);
+        Some(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::hash_all;
+    use crate::{alg::Algorithm, temp::TempDir};
+
+    #[test]
+    fn results_match_the_blocking_path() {
+        let temp = TempDir::new("uring").unwrap();
+        let dir = temp.path();
+
+        let mut paths = Vec::new();
+        for n in 0..200usize {
+            let path = dir.join(n.to_string());
+            fs::write(&path, vec![n as u8; n * 4099]).unwrap();
+            paths.push(path);
+        }
+        paths.push(dir.join("missing"));
+
+        // Where io_uring is unavailable there is nothing to compare.
+        let Some(results) = hash_all(&paths, Algorithm::Sha256) else {
+            return;
+        };
+
+        for (path, result) in paths.iter().zip(results) {
+            let result = result.expect("every file is hashed");
+            match Algorithm::Sha256.hash(path) {
+                Ok(expected) => assert_eq!(expected, result.unwrap()),
+                Err(e) => assert_eq!(e.kind(), result.unwrap_err().kind()),
+            }
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! Filtering for directory walks.
+//!
+//! Patterns use gitignore syntax throughout, whether they come from the command line, from
+//! `.checksumignore` files or from `.gitignore` files. Each walk matches patterns against paths
+//! relative to its own root, so the two sides of a directory comparison are filtered alike. The
+//! same goes for hidden files and directories.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::error::{Context, Error, Operation};
+
+static CHECKSUM_IGNORE: &str = ".checksumignore";
+static GIT_IGNORE: &str = ".gitignore";
+
+/// Report a part of a tree which could not be walked.
+///
+/// Unless errors are being skipped, this is an I/O error like any other.
+pub fn report_error(skip_errors: bool, message: impl fmt::Display) {
+    if skip_errors {
+        eprintln!("warning: {message}");
+    } else {
+        crate::error::report_io_error(message);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WalkOptions {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub respect_gitignore: bool,
+    /// Visit files whose names begin with a dot.
+    pub hidden_files: bool,
+    /// Descend into directories whose names begin with a dot.
+    pub hidden_dirs: bool,
+    pub symlinks: SymlinkPolicy,
+    /// Treat unreadable parts of the tree as warnings rather than errors.
+    pub skip_errors: bool,
+}
+
+impl WalkOptions {
+    /// Options for operations which read file contents. A link has no content of its own, so
+    /// where links would otherwise be compared by target, they are skipped instead.
+    pub fn for_contents(&self) -> Self {
+        let symlinks = match self.symlinks {
+            SymlinkPolicy::CompareTarget => SymlinkPolicy::Skip,
+            policy => policy,
+        };
+
+        Self {
+            symlinks,
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            respect_gitignore: false,
+            hidden_files: false,
+            hidden_dirs: true,
+            symlinks: SymlinkPolicy::default(),
+            skip_errors: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Follow links to files and directories within the root of the walk.
+    Follow,
+    /// Ignore links entirely.
+    #[default]
+    Skip,
+    /// Treat links as entries in their own right, equal only if their targets are equal.
+    CompareTarget,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymlinkPolicy::Follow => f.write_str("follow"),
+            SymlinkPolicy::Skip => f.write_str("skip"),
+            SymlinkPolicy::CompareTarget => f.write_str("compare-target"),
+        }
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = UnknownSymlinkPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "compare-target" => Ok(SymlinkPolicy::CompareTarget),
+            _ => Err(UnknownSymlinkPolicyError(s.into())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UnknownSymlinkPolicyError(String);
+
+impl fmt::Display for UnknownSymlinkPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown symlink policy (expected follow, skip or compare-target): {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownSymlinkPolicyError {}
+
+/// Decides which entries a walk should visit.
+///
+/// Ignore files are loaded as the walk descends into each directory, and so entries must be
+/// presented in the order in which the walk visits them.
+pub struct Filter {
+    root: Option<PathBuf>,
+    include: Option<Gitignore>,
+    exclude: Gitignore,
+    respect_gitignore: bool,
+    hidden_files: bool,
+    hidden_dirs: bool,
+    ignores: Vec<(PathBuf, Gitignore)>,
+}
+
+impl Filter {
+    pub fn new(root: &Path, options: &WalkOptions) -> crate::Result<Self> {
+        let include = if options.include.is_empty() {
+            None
+        } else {
+            Some(build_patterns(root, &options.include)?)
+        };
+
+        // Links are only ever followed within the root, so we'll need to know where that is.
+        let canonical_root = match options.symlinks {
+            SymlinkPolicy::Follow => Some(root.canonicalize().context(Operation::Walk, root)?),
+            _ => None,
+        };
+
+        Ok(Self {
+            root: canonical_root,
+            include,
+            exclude: build_patterns(root, &options.exclude)?,
+            respect_gitignore: options.respect_gitignore,
+            hidden_files: options.hidden_files,
+            hidden_dirs: options.hidden_dirs,
+            ignores: Vec::new(),
+        })
+    }
+
+    /// True if the walk should visit `path`. Rejecting a directory prunes everything beneath it.
+    ///
+    /// The root of the walk is at depth zero and is never considered hidden, since the user
+    /// asked for it by name.
+    pub fn accepts(&mut self, path: &Path, is_dir: bool, depth: usize) -> bool {
+        // Ignore files apply only beneath the directory in which they were found.
+        while let Some((dir, _)) = self.ignores.last() {
+            if path.starts_with(dir) && path != dir {
+                break;
+            }
+            self.ignores.pop();
+        }
+
+        if depth > 0 && is_hidden(path) {
+            let allowed = if is_dir {
+                self.hidden_dirs
+            } else {
+                self.hidden_files
+            };
+
+            if !allowed {
+                return false;
+            }
+        }
+
+        if self.exclude.matched(path, is_dir).is_ignore() {
+            return false;
+        }
+
+        // The innermost ignore file to express an opinion wins.
+        for (_, ignore) in self.ignores.iter().rev() {
+            let matched = ignore.matched(path, is_dir);
+            if matched.is_ignore() {
+                return false;
+            }
+            if matched.is_whitelist() {
+                break;
+            }
+        }
+
+        if is_dir {
+            if let Some(ignore) = self.load_ignore_files(path) {
+                self.ignores.push((path.to_owned(), ignore));
+            }
+            return true;
+        }
+
+        match &self.include {
+            Some(include) => include.matched_path_or_any_parents(path, false).is_ignore(),
+            None => true,
+        }
+    }
+
+    /// True if the link at `path` may be followed, which is to say that it resolves to
+    /// something within the root of the walk.
+    pub fn admits_link(&self, path: &Path) -> io::Result<bool> {
+        let Some(root) = &self.root else {
+            return Ok(false);
+        };
+
+        Ok(path.canonicalize()?.starts_with(root))
+    }
+
+    fn load_ignore_files(&self, dir: &Path) -> Option<Gitignore> {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+
+        // Later files take precedence, so .checksumignore overrides .gitignore.
+        let names = if self.respect_gitignore {
+            &[GIT_IGNORE, CHECKSUM_IGNORE][..]
+        } else {
+            &[CHECKSUM_IGNORE][..]
+        };
+
+        for name in names {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+
+            found = true;
+            if let Some(e) = builder.add(&path) {
+                eprintln!("warning: {e}");
+            }
+        }
+
+        if !found {
+            return None;
+        }
+
+        match builder.build() {
+            Ok(ignore) => Some(ignore),
+            Err(e) => {
+                eprintln!("warning: {e}");
+                None
+            }
+        }
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+}
+
+fn build_patterns(root: &Path, patterns: &[String]) -> crate::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .map_err(|e| Error::Pattern(e.to_string()))?;
+    }
+    builder.build().map_err(|e| Error::Pattern(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Filter, SymlinkPolicy, WalkOptions};
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        let options = WalkOptions {
+            include: include.iter().map(|&s| s.into()).collect(),
+            exclude: exclude.iter().map(|&s| s.into()).collect(),
+            ..Default::default()
+        };
+        Filter::new(Path::new("root"), &options).unwrap()
+    }
+
+    #[test]
+    fn symlink_policies_round_trip() {
+        for policy in [
+            SymlinkPolicy::Follow,
+            SymlinkPolicy::Skip,
+            SymlinkPolicy::CompareTarget,
+        ] {
+            assert_eq!(Ok(policy), policy.to_string().parse().map_err(|_| ()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_links_resolving_within_the_root_are_admitted() {
+        use std::{fs, os::unix::fs::symlink};
+
+        let temp = crate::temp::TempDir::new("admit").unwrap();
+        let root = temp.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("inside"), "").unwrap();
+        fs::write(temp.path().join("outside"), "").unwrap();
+
+        symlink(root.join("inside"), root.join("in")).unwrap();
+        symlink(temp.path().join("outside"), root.join("out")).unwrap();
+        symlink("../root/inside", root.join("round")).unwrap();
+        symlink("missing", root.join("broken")).unwrap();
+
+        let options = WalkOptions {
+            symlinks: SymlinkPolicy::Follow,
+            ..Default::default()
+        };
+        let filter = Filter::new(&root, &options).unwrap();
+
+        assert!(filter.admits_link(&root.join("in")).unwrap());
+        assert!(filter.admits_link(&root.join("round")).unwrap());
+        assert!(!filter.admits_link(&root.join("out")).unwrap());
+        assert!(filter.admits_link(&root.join("broken")).is_err());
+    }
+
+    #[test]
+    fn excluded_directories_are_pruned() {
+        let mut filter = filter(&[], &["node_modules/", "*.tmp"]);
+        assert!(!filter.accepts(Path::new("root/node_modules"), true, 1));
+        assert!(filter.accepts(Path::new("root/node_modules"), false, 1));
+        assert!(!filter.accepts(Path::new("root/src/scratch.tmp"), false, 2));
+        assert!(filter.accepts(Path::new("root/src/main.rs"), false, 2));
+    }
+
+    #[test]
+    fn includes_apply_to_files_only() {
+        let mut filter = filter(&["*.rs", "docs/"], &[]);
+        assert!(filter.accepts(Path::new("root/src"), true, 1));
+        assert!(filter.accepts(Path::new("root/src/main.rs"), false, 2));
+        assert!(!filter.accepts(Path::new("root/src/notes.txt"), false, 2));
+        assert!(filter.accepts(Path::new("root/docs/notes.txt"), false, 2));
+    }
+
+    #[test]
+    fn hidden_files_are_skipped_by_default() {
+        let mut filter = filter(&[], &[]);
+        assert!(!filter.accepts(Path::new("root/.env"), false, 1));
+        assert!(filter.accepts(Path::new("root/.git"), true, 1));
+    }
+
+    #[test]
+    fn hidden_policy_is_configurable() {
+        let options = WalkOptions {
+            hidden_files: true,
+            hidden_dirs: false,
+            ..Default::default()
+        };
+        let mut filter = Filter::new(Path::new("root"), &options).unwrap();
+        assert!(filter.accepts(Path::new("root/.env"), false, 1));
+        assert!(!filter.accepts(Path::new("root/.git"), true, 1));
+    }
+
+    #[test]
+    fn the_root_is_never_hidden() {
+        let options = WalkOptions {
+            hidden_dirs: false,
+            ..Default::default()
+        };
+        let mut filter = Filter::new(Path::new(".config"), &options).unwrap();
+        assert!(filter.accepts(Path::new(".config"), true, 0));
+    }
+
+    #[test]
+    fn bad_patterns_are_rejected() {
+        let options = WalkOptions {
+            exclude: vec!["a{b".into()],
+            ..Default::default()
+        };
+        assert!(Filter::new(Path::new("root"), &options).is_err());
+    }
+}